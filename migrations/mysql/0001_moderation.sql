@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS `kleinerbot_cases` (
+    `guild_id` BIGINT UNSIGNED NOT NULL,
+    `number` BIGINT UNSIGNED NOT NULL,
+    `action` VARCHAR(16) NOT NULL,
+    `moderator_id` BIGINT UNSIGNED NOT NULL,
+    `target_id` BIGINT UNSIGNED NOT NULL,
+    `reason` TEXT NOT NULL,
+    `duration` BIGINT UNSIGNED NULL,
+    `created_at` BIGINT UNSIGNED NOT NULL,
+    `log_message_id` BIGINT UNSIGNED NULL,
+    PRIMARY KEY (`guild_id`, `number`),
+    KEY `target` (`guild_id`, `target_id`)
+);
+
+-- Timed mutes and bans waiting to be lifted by the scheduler
+CREATE TABLE IF NOT EXISTS `kleinerbot_punishments` (
+    `guild_id` BIGINT UNSIGNED NOT NULL,
+    `target_id` BIGINT UNSIGNED NOT NULL,
+    `action` VARCHAR(16) NOT NULL,
+    `case_number` BIGINT UNSIGNED NOT NULL,
+    `expires_at` BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`guild_id`, `target_id`, `action`),
+    KEY `expires` (`expires_at`)
+);
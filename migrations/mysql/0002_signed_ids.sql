@@ -0,0 +1,15 @@
+-- Same column types as SQLite, ids and timestamps all fit in a signed BIGINT
+ALTER TABLE `kleinerbot_cases`
+    MODIFY `guild_id` BIGINT NOT NULL,
+    MODIFY `number` BIGINT NOT NULL,
+    MODIFY `moderator_id` BIGINT NOT NULL,
+    MODIFY `target_id` BIGINT NOT NULL,
+    MODIFY `duration` BIGINT NULL,
+    MODIFY `created_at` BIGINT NOT NULL,
+    MODIFY `log_message_id` BIGINT NULL;
+
+ALTER TABLE `kleinerbot_punishments`
+    MODIFY `guild_id` BIGINT NOT NULL,
+    MODIFY `target_id` BIGINT NOT NULL,
+    MODIFY `case_number` BIGINT NOT NULL,
+    MODIFY `expires_at` BIGINT NOT NULL;
@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS `kleinerbot_cases` (
+    `guild_id` BIGINT NOT NULL,
+    `number` BIGINT NOT NULL,
+    `action` VARCHAR(16) NOT NULL,
+    `moderator_id` BIGINT NOT NULL,
+    `target_id` BIGINT NOT NULL,
+    `reason` TEXT NOT NULL,
+    `duration` BIGINT NULL,
+    `created_at` BIGINT NOT NULL,
+    `log_message_id` BIGINT NULL,
+    PRIMARY KEY (`guild_id`, `number`)
+);
+
+CREATE INDEX IF NOT EXISTS `kleinerbot_cases_target` ON `kleinerbot_cases` (`guild_id`, `target_id`);
+
+-- Timed mutes and bans waiting to be lifted by the scheduler
+CREATE TABLE IF NOT EXISTS `kleinerbot_punishments` (
+    `guild_id` BIGINT NOT NULL,
+    `target_id` BIGINT NOT NULL,
+    `action` VARCHAR(16) NOT NULL,
+    `case_number` BIGINT NOT NULL,
+    `expires_at` BIGINT NOT NULL,
+    PRIMARY KEY (`guild_id`, `target_id`, `action`)
+);
+
+CREATE INDEX IF NOT EXISTS `kleinerbot_punishments_expires` ON `kleinerbot_punishments` (`expires_at`);
@@ -0,0 +1 @@
+-- Nothing to do, SQLite integers are always signed
@@ -0,0 +1,7 @@
+-- Overrides of config.yaml set with the config command, one row per changed setting
+CREATE TABLE IF NOT EXISTS `kleinerbot_guild_settings` (
+    `guild_id` BIGINT NOT NULL,
+    `name` VARCHAR(32) NOT NULL,
+    `value` TEXT NOT NULL,
+    PRIMARY KEY (`guild_id`, `name`)
+);
@@ -0,0 +1,6 @@
+-- Guilds in raid mode, so a restart can still turn it off and restore the verification level
+CREATE TABLE IF NOT EXISTS `kleinerbot_raids` (
+    `guild_id` BIGINT NOT NULL PRIMARY KEY,
+    `previous_verification` BIGINT NULL,
+    `started_at` BIGINT NOT NULL
+);
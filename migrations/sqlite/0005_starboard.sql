@@ -0,0 +1,6 @@
+-- Starboard post of every starred message, so a restart doesn't repost them
+CREATE TABLE IF NOT EXISTS `kleinerbot_starboard` (
+    `message_id` BIGINT NOT NULL PRIMARY KEY,
+    `guild_id` BIGINT NOT NULL,
+    `post_id` BIGINT NOT NULL
+);
@@ -0,0 +1,398 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::{error, info, warn};
+use sqlx::{any::AnyPool, Row};
+use twilight_embed_builder::EmbedBuilder;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+use twilight_http::Client as TwilightHttp;
+use twilight_model::{
+    guild::{Permissions, VerificationLevel},
+    id::{ChannelId, GuildId, UserId},
+};
+
+use crate::commands::{ArgKind, ArgSpec, Args, Command, Commands, Context};
+use crate::db::get_u64;
+use crate::events::Module;
+use crate::guild_settings::{GuildSettings, SettingsStore};
+use crate::utils::{
+    config::{Config, RaidAction},
+    snowflake,
+};
+
+#[derive(Clone, Copy)]
+struct Raid {
+    // Level to restore once the raid is over, None if it wasn't raised
+    verification: Option<VerificationLevel>,
+}
+
+fn verification_level(level: i64) -> Option<VerificationLevel> {
+    match level {
+        0 => Some(VerificationLevel::None),
+        1 => Some(VerificationLevel::Low),
+        2 => Some(VerificationLevel::Medium),
+        3 => Some(VerificationLevel::High),
+        4 => Some(VerificationLevel::VeryHigh),
+        _ => None,
+    }
+}
+
+/// Guilds currently in raid mode, shared with the `raid` command and kept in the database if there is one
+#[derive(Clone, Default)]
+pub struct RaidState {
+    active: Arc<Mutex<HashMap<GuildId, Raid>>>,
+    pool: Option<AnyPool>,
+}
+
+impl RaidState {
+    /// Picks up raids that were still on when the bot stopped, a database error only loses those
+    pub async fn load(pool: Option<AnyPool>) -> Self {
+        let state = Self {
+            active: Arc::default(),
+            pool,
+        };
+
+        if let Some(pool) = &state.pool {
+            let loaded: Result<()> = try {
+                let rows = sqlx::query("SELECT guild_id,previous_verification FROM `kleinerbot_raids`")
+                    .fetch_all(pool)
+                    .await?;
+
+                for row in rows {
+                    let guild_id = GuildId(get_u64(&row, "guild_id")?);
+                    let previous: Option<i64> = row.try_get("previous_verification")?;
+                    let raid = Raid {
+                        verification: previous.and_then(verification_level),
+                    };
+
+                    warn!("Antiraid: Raid mode still on in {}", guild_id);
+                    state.start(guild_id, raid);
+                }
+            };
+
+            loaded.unwrap_or_else(|err| {
+                error!("Antiraid: Loading raid mode failed, check the raids from before the restart: {:#}", err)
+            });
+        }
+
+        state
+    }
+
+    pub fn is_active(&self, guild_id: GuildId) -> bool {
+        self.active.lock().unwrap().contains_key(&guild_id)
+    }
+
+    fn start(&self, guild_id: GuildId, raid: Raid) {
+        self.active.lock().unwrap().insert(guild_id, raid);
+    }
+
+    async fn save(&self, guild_id: GuildId, raid: Raid) -> Result<()> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+
+        // One transaction, so a failed insert doesn't drop the raid from before
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM `kleinerbot_raids` WHERE guild_id = ?")
+            .bind(guild_id.0 as i64)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO `kleinerbot_raids` (guild_id,previous_verification,started_at) VALUES (?, ?, ?)",
+        )
+        .bind(guild_id.0 as i64)
+        .bind(raid.verification.map(|level| level as i64))
+        .bind(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn stop(&self, guild_id: GuildId) -> Result<Option<Raid>> {
+        let raid = self.active.lock().unwrap().get(&guild_id).copied();
+
+        // Forgotten only once the row is gone, otherwise the next start would bring the raid back
+        if let (Some(_), Some(pool)) = (raid, &self.pool) {
+            sqlx::query("DELETE FROM `kleinerbot_raids` WHERE guild_id = ?")
+                .bind(guild_id.0 as i64)
+                .execute(pool)
+                .await?;
+        }
+
+        self.active.lock().unwrap().remove(&guild_id);
+
+        Ok(raid)
+    }
+}
+
+async fn alert(
+    http: &TwilightHttp,
+    settings: &GuildSettings,
+    config: &Config,
+    text: String,
+) -> Result<()> {
+    let channel = match settings.antiraid_alert_channel(config) {
+        Some(channel) => ChannelId(channel),
+        None => return Ok(()),
+    };
+
+    let embed = EmbedBuilder::new()
+        .color(0xb90702)?
+        .title("Raid detected")?
+        .description(text)?
+        .build()?;
+
+    let mut message = http.create_message(channel).embed(embed)?;
+
+    if let Some(role) = settings.antiraid_alert_role(config) {
+        message = message.content(format!("<@&{}>", role))?;
+    }
+
+    message.await?;
+
+    Ok(())
+}
+
+async fn start_raid(
+    http: &TwilightHttp,
+    config: &Config,
+    settings: &SettingsStore,
+    state: &RaidState,
+    guild_id: GuildId,
+    joins: usize,
+) -> Result<()> {
+    let mut raid = Raid { verification: None };
+
+    // Marked active first so the next joins don't trigger it again
+    state.start(guild_id, raid);
+
+    warn!(
+        "Antiraid: Raid mode on in {} after {} joins",
+        guild_id, joins
+    );
+
+    // A failed raise mustn't keep the moderators from being alerted
+    let mut raise_failed = false;
+
+    if config.antiraid_actions.contains(&RaidAction::Verification) {
+        let raised: Result<Option<VerificationLevel>> = try {
+            let guild = http
+                .guild(guild_id)
+                .await?
+                .context("Antiraid: Guild not found")?;
+
+            if guild.verification_level != VerificationLevel::VeryHigh {
+                http.update_guild(guild_id)
+                    .verification_level(VerificationLevel::VeryHigh)
+                    .await?;
+                Some(guild.verification_level)
+            } else {
+                None
+            }
+        };
+
+        match raised {
+            Ok(previous) => {
+                raid.verification = previous;
+                state.start(guild_id, raid);
+            }
+            Err(err) => {
+                warn!("Antiraid: Raising verification in {} failed: {}", guild_id, err);
+                raise_failed = true;
+            }
+        }
+    }
+
+    // Without a database this is the only record of the level to go back to
+    if let Some(level) = raid.verification {
+        warn!(
+            "Antiraid: Verification level of {} was {:?} before the raid",
+            guild_id, level
+        );
+    }
+
+    state
+        .save(guild_id, raid)
+        .await
+        .unwrap_or_else(|err| warn!("Antiraid: Saving raid mode of {} failed: {}", guild_id, err));
+
+    if config.antiraid_actions.contains(&RaidAction::Alert) {
+        let mut text = format!(
+            "{} suspicious joins within {}s, raid mode is on.",
+            joins, config.antiraid_window
+        );
+        if raid.verification.is_some() {
+            text += "\nVerification level raised to the highest.";
+        }
+        if raise_failed {
+            text += "\nRaising the verification level failed, check the Manage Server permission.";
+        }
+        if config.antiraid_actions.contains(&RaidAction::Kick) {
+            text += "\nNew members are kicked on join.";
+        }
+        text += "\nUse `raid off` once it's over.";
+
+        let guild_settings = settings.get(guild_id).await;
+        alert(http, &guild_settings, config, text).await?;
+    }
+
+    Ok(())
+}
+
+pub struct Antiraid {
+    http: TwilightHttp,
+    config: Config,
+    settings: SettingsStore,
+    state: RaidState,
+    window: Duration,
+    min_age: Option<Duration>,
+    // Join times per guild, flagged when the account is younger than min_age
+    joins: HashMap<GuildId, VecDeque<(Instant, bool)>>,
+}
+
+impl Antiraid {
+    pub fn new(config: Config, settings: SettingsStore, state: RaidState) -> Self {
+        Self {
+            http: TwilightHttp::new(&config.discord.token),
+            window: Duration::from_secs(config.antiraid_window),
+            min_age: config.antiraid_min_account_age.map(Duration::from_secs),
+            joins: HashMap::new(),
+            config,
+            settings,
+            state,
+        }
+    }
+
+    async fn member_add(&mut self, guild_id: GuildId, user_id: UserId) -> Result<()> {
+        let config = &self.config;
+        let http = &self.http;
+
+        if self.state.is_active(guild_id) {
+            if config.antiraid_actions.contains(&RaidAction::Kick) {
+                info!("Antiraid: Kicking {} from {}", user_id, guild_id);
+                http.remove_guild_member(guild_id, user_id).await?;
+            }
+            return Ok(());
+        }
+
+        let min_age = self.min_age;
+        let window = self.window;
+        let young = min_age.map_or(false, |min_age| snowflake::age(user_id.0) < min_age);
+
+        let recent = self.joins.entry(guild_id).or_default();
+        while recent
+            .front()
+            .map_or(false, |(time, _)| time.elapsed() > window)
+        {
+            recent.pop_front();
+        }
+        recent.push_back((Instant::now(), young));
+
+        let total = recent.len();
+        let young = recent.iter().filter(|(_, young)| *young).count();
+
+        let triggered = total >= config.antiraid_joins
+            || (min_age.is_some() && young >= config.antiraid_young_joins);
+
+        if triggered {
+            recent.clear();
+            start_raid(http, config, &self.settings, &self.state, guild_id, total).await?;
+
+            if config.antiraid_actions.contains(&RaidAction::Kick) {
+                http.remove_guild_member(guild_id, user_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Module for Antiraid {
+    fn name(&self) -> &'static str {
+        "Antiraid"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        _cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            if let Event::MemberAdd(member) = event {
+                self.member_add(member.guild_id, member.user.id).await?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.window = Duration::from_secs(config.antiraid_window);
+        self.min_age = config.antiraid_min_account_age.map(Duration::from_secs);
+        self.config = config.clone();
+        Ok(())
+    }
+}
+
+pub fn register(commands: &mut Commands) {
+    commands.register(Command {
+        name: "raid",
+        description: "Show raid mode, or turn it off",
+        args: &[ArgSpec::optional("off", ArgKind::Word)],
+        permissions: Permissions::MANAGE_GUILD,
+        handler: raid,
+    });
+}
+
+fn raid<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let guild_id = ctx
+            .message
+            .guild_id
+            .context("Raid mode outside of a guild")?;
+
+        match args.text(0) {
+            None => {
+                let status = if ctx.raid.is_active(guild_id) {
+                    "on"
+                } else {
+                    "off"
+                };
+                ctx.reply(format!("Raid mode is {}", status)).await
+            }
+            Some("off") => {
+                let raid = match ctx.raid.stop(guild_id).await? {
+                    Some(raid) => raid,
+                    None => return ctx.reply_error("Raid mode is not on").await,
+                };
+
+                if let Some(level) = raid.verification {
+                    ctx.http
+                        .update_guild(guild_id)
+                        .verification_level(level)
+                        .await?;
+                }
+
+                info!(
+                    "Antiraid: Raid mode off in {} by {}",
+                    guild_id, ctx.message.author.id
+                );
+
+                ctx.reply("Raid mode is off").await
+            }
+            Some(other) => ctx.reply_error(format!("Unknown option `{}`", other)).await,
+        }
+    }
+    .boxed_local()
+}
@@ -0,0 +1,328 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::{info, warn};
+use regex::Regex;
+use sqlx::any::AnyPool;
+use twilight_embed_builder::{EmbedAuthorBuilder, EmbedBuilder, EmbedFooterBuilder};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+use twilight_http::Client as TwilightHttp;
+use twilight_model::{
+    channel::Message,
+    id::{ChannelId, GuildId, UserId},
+};
+
+use crate::events::Module;
+use crate::guild_settings::SettingsStore;
+use crate::moderation::{Action, Enforcer};
+use crate::utils::config::{AutomodAction, AutomodRule, Config, RuleKind};
+
+const INVITE_PATTERN: &str = r"(?i)(discord\.gg|discord(app)?\.com/invite)/[a-z0-9-]+";
+
+enum Check {
+    Words(Vec<Regex>),
+    Invites(Regex),
+    Mentions(usize),
+    Spam { repeats: usize, window: Duration },
+    Caps { ratio: f32, min_length: usize },
+    Attachments(Vec<String>),
+}
+
+struct Rule {
+    config: AutomodRule,
+    check: Check,
+}
+
+impl Rule {
+    fn compile(config: &AutomodRule) -> Result<Rule> {
+        let check = match &config.kind {
+            RuleKind::Words { patterns } => Check::Words(
+                patterns
+                    .iter()
+                    .map(|pattern| Regex::new(pattern))
+                    .collect::<Result<_, _>>()?,
+            ),
+            RuleKind::Invites => Check::Invites(Regex::new(INVITE_PATTERN)?),
+            RuleKind::Mentions { max } => Check::Mentions(*max),
+            RuleKind::Spam { repeats, window } => Check::Spam {
+                repeats: *repeats,
+                window: Duration::from_secs(*window),
+            },
+            RuleKind::Caps { ratio, min_length } => Check::Caps {
+                ratio: *ratio,
+                min_length: *min_length,
+            },
+            RuleKind::Attachments { extensions } => Check::Attachments(
+                extensions
+                    .iter()
+                    .map(|extension| extension.to_lowercase())
+                    .collect(),
+            ),
+        };
+
+        Ok(Rule {
+            config: config.clone(),
+            check,
+        })
+    }
+
+    fn exempt(&self, message: &Message) -> bool {
+        if self.config.exempt_channels.contains(&message.channel_id.0) {
+            return true;
+        }
+
+        message.member.as_ref().map_or(false, |member| {
+            member
+                .roles
+                .iter()
+                .any(|role| self.config.exempt_roles.contains(&role.0))
+        })
+    }
+
+    fn matches(&self, message: &Message, history: &VecDeque<(Instant, String)>) -> bool {
+        match &self.check {
+            Check::Words(patterns) => patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&message.content)),
+            Check::Invites(pattern) => pattern.is_match(&message.content),
+            Check::Mentions(max) => {
+                let everyone = if message.mention_everyone { 1 } else { 0 };
+                message.mentions.len() + message.mention_roles.len() + everyone > *max
+            }
+            Check::Spam { repeats, window } => {
+                let content = message.content.trim().to_lowercase();
+                !content.is_empty()
+                    && history
+                        .iter()
+                        .filter(|(time, old)| time.elapsed() <= *window && *old == content)
+                        .count()
+                        + 1
+                        >= *repeats
+            }
+            Check::Caps { ratio, min_length } => {
+                let letters: Vec<char> = message
+                    .content
+                    .chars()
+                    .filter(|c| c.is_alphabetic())
+                    .collect();
+                let upper = letters.iter().filter(|c| c.is_uppercase()).count();
+                letters.len() >= *min_length && upper as f32 / letters.len() as f32 >= *ratio
+            }
+            Check::Attachments(extensions) => message.attachments.iter().any(|attach| {
+                attach
+                    .filename
+                    .rsplit('.')
+                    .next()
+                    .map_or(false, |extension| {
+                        extensions.contains(&extension.to_lowercase())
+                    })
+            }),
+        }
+    }
+}
+
+async fn log_trigger(
+    http: &TwilightHttp,
+    channel: ChannelId,
+    rule: &AutomodRule,
+    message: &Message,
+) -> Result<()> {
+    let mut content = message.content.clone();
+    if content.chars().count() > 1024 {
+        content = content.chars().take(1021).collect::<String>() + "...";
+    }
+
+    let embed = EmbedBuilder::new()
+        .color(0xff8c00)?
+        .title(format!("Automod: {} -> {:?}", rule.name(), rule.action))?
+        .author(EmbedAuthorBuilder::new().name(&message.author.name)?)
+        .description(format!(
+            "<#{}>\n{}",
+            message.channel_id,
+            if content.is_empty() {
+                "Attachment only"
+            } else {
+                &content
+            }
+        ))?
+        .footer(EmbedFooterBuilder::new(format!(
+            "A:{} | M:{}",
+            message.author.id, message.id
+        ))?)
+        .build()?;
+
+    http.create_message(channel).embed(embed)?.await?;
+
+    Ok(())
+}
+
+pub struct Automod {
+    http: TwilightHttp,
+    config: Config,
+    rules: Vec<Rule>,
+    pool: Option<AnyPool>,
+    settings: SettingsStore,
+    bot_id: Option<UserId>,
+    spam_window: Duration,
+    // Recent messages per member for the spam rule
+    history: HashMap<(GuildId, UserId), VecDeque<(Instant, String)>>,
+}
+
+fn compile_rules(config: &Config) -> Result<Vec<Rule>> {
+    config
+        .automod_rules
+        .iter()
+        .map(Rule::compile)
+        .collect::<Result<Vec<_>>>()
+        .context("Automod rule compile failure")
+}
+
+fn spam_window(rules: &[Rule]) -> Duration {
+    rules
+        .iter()
+        .filter_map(|rule| match rule.check {
+            Check::Spam { window, .. } => Some(window),
+            _ => None,
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+impl Automod {
+    pub fn new(config: Config, pool: Option<AnyPool>, settings: SettingsStore) -> Result<Self> {
+        // Bad patterns are a config error, not something to retry
+        let rules = compile_rules(&config)?;
+        let spam_window = spam_window(&rules);
+
+        Ok(Self {
+            http: TwilightHttp::new(&config.discord.token),
+            config,
+            rules,
+            pool,
+            settings,
+            bot_id: None,
+            spam_window,
+            history: HashMap::new(),
+        })
+    }
+
+    async fn message_create(&mut self, msg: &Message) -> Result<()> {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) if !msg.author.bot => guild_id,
+            _ => return Ok(()),
+        };
+
+        // Prunes every member, so ones that stopped posting don't stay around
+        let spam_window = self.spam_window;
+        self.history.retain(|_, recent| {
+            while recent
+                .front()
+                .map_or(false, |(time, _)| time.elapsed() > spam_window)
+            {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+
+        let recent = self.history.entry((guild_id, msg.author.id)).or_default();
+
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| !rule.exempt(msg) && rule.matches(msg, recent));
+
+        recent.push_back((Instant::now(), msg.content.trim().to_lowercase()));
+
+        let rule = match rule {
+            Some(rule) => &rule.config,
+            None => return Ok(()),
+        };
+
+        info!(
+            "Automod: {} triggered by {} in {}",
+            rule.name(),
+            msg.author.id,
+            msg.channel_id
+        );
+
+        let http = &self.http;
+        let config = &self.config;
+
+        if rule.action != AutomodAction::Log {
+            http.delete_message(msg.channel_id, msg.id).await?;
+        }
+
+        let punishment = match rule.action {
+            AutomodAction::Warn => Some(Action::Warn),
+            AutomodAction::Mute => Some(Action::Mute),
+            AutomodAction::Delete | AutomodAction::Log => None,
+        };
+
+        if let Some(action) = punishment {
+            match &self.pool {
+                Some(pool) => {
+                    let bot_id = match self.bot_id {
+                        Some(bot_id) => bot_id,
+                        None => http.current_user().await?.id,
+                    };
+                    self.bot_id = Some(bot_id);
+
+                    let enforcer = Enforcer {
+                        http,
+                        pool,
+                        config,
+                        settings: &self.settings,
+                    };
+                    let reason = format!("Automod: {}", rule.name());
+                    let duration = rule.duration.map(Duration::from_secs);
+
+                    enforcer
+                        .enforce(guild_id, bot_id, msg.author.id, action, duration, &reason)
+                        .await?;
+                }
+                None => warn!(
+                    "Automod: {:?} needs a database, message only deleted",
+                    rule.action
+                ),
+            }
+        }
+
+        let log_channel = self.settings.get(guild_id).await.automod_log_channel(config);
+        if let Some(channel) = log_channel {
+            log_trigger(http, ChannelId(channel), rule, msg).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Module for Automod {
+    fn name(&self) -> &'static str {
+        "Automod"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        _cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            if let Event::MessageCreate(msg) = event {
+                self.message_create(&msg.0).await?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.rules = compile_rules(config)?;
+        self.spam_window = spam_window(&self.rules);
+        self.config = config.clone();
+        Ok(())
+    }
+}
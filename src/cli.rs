@@ -0,0 +1,287 @@
+use std::io::{self, Write};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use twilight_http::Client as TwilightHttp;
+use twilight_model::id::{ChannelId, MessageId};
+
+use crate::db;
+use crate::utils::config::Config;
+
+pub const USAGE: &str = "Usage: kleinerbot [--config <path>] [command]
+
+Commands:
+  run                                    Start the bot (default)
+  check-config                           Validate the config and exit
+  register-commands                      Register application commands with Discord
+  send --channel <id|alias> --content <text>
+                                         Post a single message
+  db migrate                             Apply pending database migrations
+  export-logs [--channel <id>] [--limit <n>]
+                                         Print a log channel's messages as JSON lines
+
+Options:
+  -c, --config <path>                    Config file, defaults to config.yaml
+  -h, --help                             Show this message";
+
+pub enum Command {
+    Run,
+    CheckConfig,
+    RegisterCommands,
+    Send { channel: String, content: String },
+    DbMigrate,
+    ExportLogs { channel: Option<u64>, limit: Option<usize> },
+    Help,
+}
+
+pub struct Cli {
+    pub config_path: String,
+    pub command: Command,
+}
+
+impl Cli {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut config_path = None;
+        let mut words = Vec::new();
+        let mut options = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => words.insert(0, "help".to_string()),
+                "-c" | "--config" => {
+                    config_path = Some(args.next().ok_or_else(|| anyhow!("{} needs a path", arg))?)
+                }
+                // Older scripts run `kleinerbot config.yaml --check-config`
+                "--check-config" => words.insert(0, "check-config".to_string()),
+                _ if arg.starts_with("--") => {
+                    let value = args.next().ok_or_else(|| anyhow!("{} needs a value", arg))?;
+                    options.push((arg, value));
+                }
+                _ => words.push(arg),
+            }
+        }
+
+        // Older invocations pass the config path as a plain argument
+        if config_path.is_none() {
+            if let Some(i) = words.iter().position(|word| word.ends_with(".yaml") || word.ends_with(".yml")) {
+                config_path = Some(words.remove(i));
+            }
+        }
+
+        let mut words = words.into_iter();
+        let command = match words.next().as_deref() {
+            None | Some("run") => Command::Run,
+            Some("help") => Command::Help,
+            Some("check-config") => Command::CheckConfig,
+            Some("register-commands") => Command::RegisterCommands,
+            Some("send") => Command::Send {
+                channel: take(&mut options, "--channel")?.ok_or_else(|| anyhow!("send needs --channel"))?,
+                content: take(&mut options, "--content")?.ok_or_else(|| anyhow!("send needs --content"))?,
+            },
+            Some("db") => match words.next().as_deref() {
+                Some("migrate") => Command::DbMigrate,
+                _ => bail!("db needs a subcommand: migrate"),
+            },
+            Some("export-logs") => Command::ExportLogs {
+                channel: take(&mut options, "--channel")?,
+                limit: take(&mut options, "--limit")?,
+            },
+            Some(other) => bail!("Unknown command {}", other),
+        };
+
+        if let Some(extra) = words.next() {
+            bail!("Unexpected argument {}", extra);
+        }
+        if let Some((option, _)) = options.first() {
+            bail!("Unknown option {}", option);
+        }
+
+        Ok(Self {
+            config_path: config_path.unwrap_or("config.yaml".to_string()),
+            command,
+        })
+    }
+}
+
+fn take<T: std::str::FromStr>(options: &mut Vec<(String, String)>, name: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match options.iter().position(|(option, _)| option == name) {
+        Some(i) => {
+            let (_, value) = options.remove(i);
+            let value = value.parse().with_context(|| format!("Invalid {} {}", name, value))?;
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn register_commands() -> Result<()> {
+    // twilight 0.2 has neither the application command routes nor the interaction event
+    bail!("Application commands need a newer twilight, prefix commands work without registering")
+}
+
+pub async fn send(config: &Config, channel: &str, content: &str) -> Result<()> {
+    // Aliases from web.channels, so scripts don't need the ids
+    let channel = match config.web.as_ref().and_then(|web| web.channels.get(channel)) {
+        Some(id) => *id,
+        None => channel
+            .parse()
+            .with_context(|| format!("{} is neither a channel id nor in web.channels", channel))?,
+    };
+
+    let http = TwilightHttp::new(&config.discord.token);
+    let message = http.create_message(ChannelId(channel)).content(content)?.await?;
+
+    info!("Sent message {} to {}", message.id, channel);
+    Ok(())
+}
+
+pub async fn db_migrate(config: &Config) -> Result<()> {
+    let pool = db::pool(config)?.context("db migrate needs the database section")?;
+
+    let applied = db::migrate(&pool).await?;
+
+    info!(
+        "Applied {} migrations, the schema is at version {}",
+        applied,
+        db::version(&pool).await?
+    );
+    Ok(())
+}
+
+// Discord returns at most this many messages per request
+const PAGE_SIZE: usize = 100;
+
+pub async fn export_logs(config: &Config, channel: Option<u64>, limit: Option<usize>) -> Result<()> {
+    let channel = ChannelId(channel.unwrap_or(config.logging.channel));
+    let http = TwilightHttp::new(&config.discord.token);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut before: Option<MessageId> = None;
+    let mut exported = 0;
+
+    // Newest first, paging backwards until the channel or the limit runs out
+    loop {
+        let page = limit.map_or(PAGE_SIZE, |limit| (limit - exported).min(PAGE_SIZE));
+        if page == 0 {
+            break;
+        }
+
+        let messages = match before {
+            Some(id) => http.channel_messages(channel).before(id).limit(page as u64)?.await?,
+            None => http.channel_messages(channel).limit(page as u64)?.await?,
+        };
+        for message in &messages {
+            writeln!(out, "{}", serde_json::to_string(message)?)?;
+        }
+
+        exported += messages.len();
+        match messages.last() {
+            Some(last) if messages.len() == page => before = Some(last.id),
+            _ => break,
+        }
+    }
+
+    info!("Exported {} messages from {}", exported, channel);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli> {
+        Cli::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{:?} parsed", args),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    #[test]
+    fn runs_by_default() {
+        let cli = parse(&[]).unwrap();
+        assert_eq!(cli.config_path, "config.yaml");
+        assert!(matches!(cli.command, Command::Run));
+    }
+
+    #[test]
+    fn config_path_option() {
+        let cli = parse(&["-c", "/etc/kleinerbot.yaml", "check-config"]).unwrap();
+        assert_eq!(cli.config_path, "/etc/kleinerbot.yaml");
+        assert!(matches!(cli.command, Command::CheckConfig));
+
+        let cli = parse(&["run", "--config", "other.yml"]).unwrap();
+        assert_eq!(cli.config_path, "other.yml");
+        assert!(matches!(cli.command, Command::Run));
+
+        assert_eq!(error(&["--config"]), "--config needs a path");
+    }
+
+    #[test]
+    fn legacy_invocations() {
+        let cli = parse(&["config.yaml", "--check-config"]).unwrap();
+        assert_eq!(cli.config_path, "config.yaml");
+        assert!(matches!(cli.command, Command::CheckConfig));
+
+        let cli = parse(&["old.yml"]).unwrap();
+        assert_eq!(cli.config_path, "old.yml");
+        assert!(matches!(cli.command, Command::Run));
+    }
+
+    #[test]
+    fn help() {
+        assert!(matches!(parse(&["--help"]).unwrap().command, Command::Help));
+        assert!(matches!(parse(&["-c", "other.yaml", "-h"]).unwrap().command, Command::Help));
+    }
+
+    #[test]
+    fn send_options() {
+        let cli = parse(&["send", "--content", "hello there", "--channel", "general"]).unwrap();
+        match cli.command {
+            Command::Send { channel, content } => {
+                assert_eq!(channel, "general");
+                assert_eq!(content, "hello there");
+            }
+            _ => panic!("not send"),
+        }
+
+        assert_eq!(error(&["send", "--channel", "general"]), "send needs --content");
+        assert_eq!(error(&["send", "--channel"]), "--channel needs a value");
+    }
+
+    #[test]
+    fn db_migrate() {
+        assert!(matches!(parse(&["db", "migrate"]).unwrap().command, Command::DbMigrate));
+        assert_eq!(error(&["db"]), "db needs a subcommand: migrate");
+        assert_eq!(error(&["db", "drop"]), "db needs a subcommand: migrate");
+    }
+
+    #[test]
+    fn export_logs_options() {
+        match parse(&["export-logs", "--limit", "50"]).unwrap().command {
+            Command::ExportLogs { channel, limit } => {
+                assert_eq!(channel, None);
+                assert_eq!(limit, Some(50));
+            }
+            _ => panic!("not export-logs"),
+        }
+
+        assert!(error(&["export-logs", "--limit", "lots"]).starts_with("Invalid --limit lots"));
+    }
+
+    #[test]
+    fn unknown_commands_and_options() {
+        assert_eq!(error(&["start"]), "Unknown command start");
+        assert_eq!(error(&["run", "now"]), "Unexpected argument now");
+        assert_eq!(error(&["run", "--verbose", "yes"]), "Unknown option --verbose");
+        assert_eq!(error(&["export-logs", "--content", "hi"]), "Unknown option --content");
+    }
+}
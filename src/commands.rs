@@ -0,0 +1,291 @@
+mod args;
+mod builtin;
+
+pub use self::args::{format_duration, parse as parse_args, Arg, ArgKind, ArgSpec, Args};
+
+use std::time::Instant;
+
+use anyhow::{Context as _, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::info;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_embed_builder::EmbedBuilder;
+use twilight_gateway::Event;
+use twilight_http::Client as TwilightHttp;
+use twilight_model::{
+    channel::{embed::Embed, Message},
+    guild::Permissions,
+    id::RoleId,
+};
+
+use sqlx::any::AnyPool;
+
+use crate::antiraid::{self, RaidState};
+use crate::events::Module;
+use crate::guild_settings::{self, SettingsStore};
+use crate::moderation;
+use crate::mysql::ServerList;
+use crate::utils::config::Config;
+
+pub type Handler = for<'a> fn(&'a Context<'a>, Args) -> LocalBoxFuture<'a, Result<()>>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub args: &'static [ArgSpec],
+    // Empty means everyone may run it
+    pub permissions: Permissions,
+    pub handler: Handler,
+}
+
+impl Command {
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("{}{}", prefix, self.name);
+        for arg in self.args {
+            usage += " ";
+            usage += &arg.usage();
+        }
+        usage
+    }
+}
+
+/// Commands contributed by every module
+#[derive(Default)]
+pub struct Commands(Vec<Command>);
+
+impl Commands {
+    pub fn register(&mut self, command: Command) {
+        self.0.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.0
+            .iter()
+            .find(|command| command.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.0.iter()
+    }
+}
+
+pub struct Context<'a> {
+    pub http: &'a TwilightHttp,
+    pub cache: &'a InMemoryCache,
+    pub config: &'a Config,
+    pub message: &'a Message,
+    pub prefix: &'a str,
+    pub commands: &'a Commands,
+    pub permissions: Permissions,
+    pub servers: &'a ServerList,
+    pub db: Option<&'a AnyPool>,
+    pub settings: &'a SettingsStore,
+    pub raid: &'a RaidState,
+    pub started: Instant,
+}
+
+impl<'a> Context<'a> {
+    pub async fn reply(&self, content: impl Into<String>) -> Result<()> {
+        self.http
+            .create_message(self.message.channel_id)
+            .content(content)?
+            .await?;
+        Ok(())
+    }
+
+    pub async fn reply_embed(&self, embed: Embed) -> Result<()> {
+        self.http
+            .create_message(self.message.channel_id)
+            .embed(embed)?
+            .await?;
+        Ok(())
+    }
+
+    pub async fn reply_error(&self, text: impl Into<String>) -> Result<()> {
+        let embed = EmbedBuilder::new()
+            .color(0xb90702)?
+            .description(text)?
+            .build()?;
+        self.reply_embed(embed).await
+    }
+}
+
+// Guild owner and administrators get everything, others the union of their roles
+fn member_permissions(cache: &InMemoryCache, message: &Message) -> Permissions {
+    let guild_id = match message.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Permissions::empty(),
+    };
+
+    if let Some(guild) = cache.guild(guild_id) {
+        if guild.owner_id == message.author.id {
+            return Permissions::all();
+        }
+    }
+
+    let roles = match &message.member {
+        Some(member) => member.roles.clone(),
+        None => cache
+            .member(guild_id, message.author.id)
+            .map(|member| member.roles.clone())
+            .unwrap_or_default(),
+    };
+
+    let mut permissions = cache
+        .role(RoleId(guild_id.0))
+        .map_or(Permissions::empty(), |everyone| everyone.permissions);
+
+    for role in roles {
+        if let Some(role) = cache.role(role) {
+            permissions |= role.permissions;
+        }
+    }
+
+    if permissions.contains(Permissions::ADMINISTRATOR) {
+        Permissions::all()
+    } else {
+        permissions
+    }
+}
+
+async fn dispatch(ctx: &Context<'_>, input: &str) -> Result<()> {
+    let (name, rest) = match input.find(char::is_whitespace) {
+        Some(end) => (&input[..end], &input[end..]),
+        None => (input, ""),
+    };
+
+    let command = match ctx.commands.find(name) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    if !ctx.permissions.contains(command.permissions) {
+        return ctx
+            .reply_error(format!(
+                "You need {:?} to use this command",
+                command.permissions
+            ))
+            .await;
+    }
+
+    match args::parse(command.args, rest) {
+        Ok(args) => {
+            info!(
+                "Commands: {} ran {} in {}",
+                ctx.message.author.id, command.name, ctx.message.channel_id
+            );
+            (command.handler)(ctx, args).await
+        }
+        Err(err) => {
+            ctx.reply_error(format!("{}\nUsage: `{}`", err, command.usage(ctx.prefix)))
+                .await
+        }
+    }
+}
+
+pub struct Router {
+    http: TwilightHttp,
+    config: Config,
+    commands: Commands,
+    servers: ServerList,
+    db: Option<AnyPool>,
+    settings: SettingsStore,
+    raid: RaidState,
+    started: Instant,
+}
+
+impl Router {
+    pub fn new(
+        config: Config,
+        servers: ServerList,
+        db: Option<AnyPool>,
+        settings: SettingsStore,
+        raid: RaidState,
+    ) -> Self {
+        let mut commands = Commands::default();
+        builtin::register(&mut commands);
+
+        if db.is_some() {
+            moderation::register(&mut commands);
+            guild_settings::register(&mut commands);
+        }
+
+        if config.antiraid_enabled {
+            antiraid::register(&mut commands);
+        }
+
+        Self {
+            http: TwilightHttp::new(&config.discord.token),
+            config,
+            commands,
+            servers,
+            db,
+            settings,
+            raid,
+            started: Instant::now(),
+        }
+    }
+
+    async fn message_create(&self, cache: &InMemoryCache, msg: &Message) -> Result<()> {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) if !msg.author.bot => guild_id,
+            _ => return Ok(()),
+        };
+
+        let settings = self.settings.get(guild_id).await;
+        let prefix = settings
+            .prefix
+            .as_deref()
+            .unwrap_or_else(|| self.config.command_prefix(guild_id.0));
+
+        let input = match msg.content.strip_prefix(prefix) {
+            Some(input) if !input.trim().is_empty() => input.trim(),
+            _ => return Ok(()),
+        };
+
+        let ctx = Context {
+            http: &self.http,
+            cache,
+            config: &self.config,
+            message: msg,
+            prefix,
+            commands: &self.commands,
+            permissions: member_permissions(cache, msg),
+            servers: &self.servers,
+            db: self.db.as_ref(),
+            settings: &self.settings,
+            raid: &self.raid,
+            started: self.started,
+        };
+
+        dispatch(&ctx, input)
+            .await
+            .context(format!("Command {}", input))
+    }
+}
+
+impl Module for Router {
+    fn name(&self) -> &'static str {
+        "Commands"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            if let Event::MessageCreate(msg) = event {
+                self.message_create(cache, &msg.0).await?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.config = config.clone();
+        Ok(())
+    }
+}
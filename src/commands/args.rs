@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use twilight_model::id::{ChannelId, UserId};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    User,
+    Channel,
+    Duration,
+    Integer,
+    Word,
+    // Everything left on the line, must be the last argument
+    Rest,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+impl ArgSpec {
+    pub const fn required(name: &'static str, kind: ArgKind) -> Self {
+        ArgSpec {
+            name,
+            kind,
+            optional: false,
+        }
+    }
+
+    pub const fn optional(name: &'static str, kind: ArgKind) -> Self {
+        ArgSpec {
+            name,
+            kind,
+            optional: true,
+        }
+    }
+
+    pub fn usage(&self) -> String {
+        if self.optional {
+            format!("[{}]", self.name)
+        } else {
+            format!("<{}>", self.name)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Arg {
+    User(UserId),
+    Channel(ChannelId),
+    Duration(Duration),
+    Integer(i64),
+    Text(String),
+}
+
+/// Parsed arguments, in the order of the command's `ArgSpec`s
+#[derive(Debug, Default)]
+pub struct Args(Vec<Option<Arg>>);
+
+impl Args {
+    pub fn user(&self, index: usize) -> Option<UserId> {
+        match self.0.get(index) {
+            Some(Some(Arg::User(id))) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn channel(&self, index: usize) -> Option<ChannelId> {
+        match self.0.get(index) {
+            Some(Some(Arg::Channel(id))) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn duration(&self, index: usize) -> Option<Duration> {
+        match self.0.get(index) {
+            Some(Some(Arg::Duration(duration))) => Some(*duration),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, index: usize) -> Option<i64> {
+        match self.0.get(index) {
+            Some(Some(Arg::Integer(value))) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self, index: usize) -> Option<&str> {
+        match self.0.get(index) {
+            Some(Some(Arg::Text(text))) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+fn parse_id(input: &str, prefixes: &[&str]) -> Option<u64> {
+    if let Ok(id) = input.parse() {
+        return Some(id);
+    }
+
+    let inner = input.strip_prefix('<')?.strip_suffix('>')?;
+
+    prefixes
+        .iter()
+        .find_map(|prefix| inner.strip_prefix(prefix))
+        .and_then(|id| id.parse().ok())
+}
+
+/// Parses `1w2d3h4m5s` style durations, a bare number is minutes
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    if let Ok(minutes) = input.parse::<u64>() {
+        return Some(Duration::from_secs(minutes.checked_mul(60)?));
+    }
+
+    let mut total = 0u64;
+    let mut number = String::new();
+
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+
+        total = total.checked_add(number.parse::<u64>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        return None;
+    }
+
+    Some(Duration::from_secs(total))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    let mut text = String::new();
+
+    for (unit, size) in &[("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if secs >= *size {
+            text += &format!("{}{}", secs / size, unit);
+            secs %= size;
+        }
+    }
+
+    if text.is_empty() {
+        "0s".to_string()
+    } else {
+        text
+    }
+}
+
+// Whitespace separated words, double quotes group words together
+fn next_word(input: &str) -> Option<(String, &str)> {
+    let input = input.trim_start();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    if let Some(quoted) = input.strip_prefix('"') {
+        if let Some(end) = quoted.find('"') {
+            return Some((quoted[..end].to_string(), &quoted[end + 1..]));
+        }
+    }
+
+    let end = input
+        .find(char::is_whitespace)
+        .unwrap_or_else(|| input.len());
+    Some((input[..end].to_string(), &input[end..]))
+}
+
+fn parse_arg(spec: &ArgSpec, word: &str) -> Result<Arg, String> {
+    let arg = match spec.kind {
+        ArgKind::User => parse_id(word, &["@!", "@"]).map(|id| Arg::User(UserId(id))),
+        ArgKind::Channel => parse_id(word, &["#"]).map(|id| Arg::Channel(ChannelId(id))),
+        ArgKind::Duration => parse_duration(word).map(Arg::Duration),
+        ArgKind::Integer => word.parse().ok().map(Arg::Integer),
+        ArgKind::Word | ArgKind::Rest => Some(Arg::Text(word.to_string())),
+    };
+
+    arg.ok_or_else(|| format!("`{}` is not a valid {}", word, spec.name))
+}
+
+pub fn parse(specs: &[ArgSpec], mut input: &str) -> Result<Args, String> {
+    let mut args = Vec::with_capacity(specs.len());
+
+    for spec in specs {
+        if spec.kind == ArgKind::Rest {
+            let rest = input.trim();
+            input = "";
+
+            if rest.is_empty() {
+                if !spec.optional {
+                    return Err(format!("Missing {}", spec.name));
+                }
+                args.push(None);
+            } else {
+                args.push(Some(Arg::Text(rest.to_string())));
+            }
+            continue;
+        }
+
+        match next_word(input) {
+            Some((word, remaining)) => match parse_arg(spec, &word) {
+                Ok(arg) => {
+                    args.push(Some(arg));
+                    input = remaining;
+                }
+                // Optional arguments may be skipped by the next one
+                Err(_) if spec.optional => args.push(None),
+                Err(err) => return Err(err),
+            },
+            None if spec.optional => args.push(None),
+            None => return Err(format!("Missing {}", spec.name)),
+        }
+    }
+
+    if !input.trim().is_empty() {
+        return Err("Too many arguments".to_string());
+    }
+
+    Ok(Args(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: ArgSpec = ArgSpec::required("user", ArgKind::User);
+    const CHANNEL: ArgSpec = ArgSpec::required("channel", ArgKind::Channel);
+    const DURATION: ArgSpec = ArgSpec::optional("duration", ArgKind::Duration);
+    const WORD: ArgSpec = ArgSpec::required("word", ArgKind::Word);
+    const REST: ArgSpec = ArgSpec::optional("reason", ArgKind::Rest);
+
+    #[test]
+    fn bare_number_is_minutes() {
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn units_add_up() {
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1W2d"), Some(Duration::from_secs(9 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn bad_durations_are_rejected() {
+        assert_eq!(parse_duration("307445734561825862"), None);
+        assert_eq!(parse_duration("99999999999999999999w"), None);
+        assert_eq!(parse_duration("5x"), None);
+        assert_eq!(parse_duration("1h5"), None);
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn durations_format_largest_unit_first() {
+        assert_eq!(format_duration(Duration::from_secs(90061)), "1d1h1m1s");
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+    }
+
+    #[test]
+    fn mentions_and_ids() {
+        for input in &["<@123>", "<@!123>", "123"] {
+            let args = parse(&[USER], input).unwrap();
+            assert_eq!(args.user(0), Some(UserId(123)));
+        }
+
+        let args = parse(&[CHANNEL], "<#456>").unwrap();
+        assert_eq!(args.channel(0), Some(ChannelId(456)));
+
+        assert!(parse(&[USER], "<#123>").is_err());
+        assert!(parse(&[CHANNEL], "<@456>").is_err());
+        assert!(parse(&[USER], "someone").is_err());
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        let args = parse(&[WORD, REST], "\"two words\" and the rest").unwrap();
+        assert_eq!(args.text(0), Some("two words"));
+        assert_eq!(args.text(1), Some("and the rest"));
+    }
+
+    #[test]
+    fn optional_arguments_can_be_skipped() {
+        let args = parse(&[USER, DURATION, REST], "<@1> spamming links").unwrap();
+        assert_eq!(args.user(0), Some(UserId(1)));
+        assert_eq!(args.duration(1), None);
+        assert_eq!(args.text(2), Some("spamming links"));
+
+        let args = parse(&[USER, DURATION, REST], "<@1> 1h").unwrap();
+        assert_eq!(args.duration(1), Some(Duration::from_secs(3600)));
+        assert_eq!(args.text(2), None);
+    }
+
+    #[test]
+    fn missing_and_extra_arguments() {
+        assert_eq!(parse(&[USER], "").unwrap_err(), "Missing user");
+        assert_eq!(parse(&[WORD], "one two").unwrap_err(), "Too many arguments");
+    }
+}
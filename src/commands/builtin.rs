@@ -0,0 +1,125 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use futures::future::{FutureExt, LocalBoxFuture};
+use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder};
+use twilight_model::guild::Permissions;
+
+use super::{format_duration, Args, Command, Commands, Context};
+
+pub fn register(commands: &mut Commands) {
+    commands.register(Command {
+        name: "help",
+        description: "List the commands you can use",
+        args: &[],
+        permissions: Permissions::empty(),
+        handler: help,
+    });
+    commands.register(Command {
+        name: "ping",
+        description: "Check that the bot responds",
+        args: &[],
+        permissions: Permissions::empty(),
+        handler: ping,
+    });
+    commands.register(Command {
+        name: "status",
+        description: "Show bot uptime and enabled modules",
+        args: &[],
+        permissions: Permissions::empty(),
+        handler: status,
+    });
+    commands.register(Command {
+        name: "servers",
+        description: "Show game servers from the last MySQL poll",
+        args: &[],
+        permissions: Permissions::empty(),
+        handler: servers,
+    });
+}
+
+fn help<'a>(ctx: &'a Context<'a>, _: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let mut embed = EmbedBuilder::new().color(0x1a7701)?.title("Commands")?;
+
+        for command in ctx.commands.iter() {
+            if ctx.permissions.contains(command.permissions) {
+                embed = embed.field(EmbedFieldBuilder::new(
+                    format!("`{}`", command.usage(ctx.prefix)),
+                    command.description,
+                )?);
+            }
+        }
+
+        ctx.reply_embed(embed.build()?).await
+    }
+    .boxed_local()
+}
+
+fn ping<'a>(ctx: &'a Context<'a>, _: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let start = Instant::now();
+
+        let reply = ctx
+            .http
+            .create_message(ctx.message.channel_id)
+            .content("Pong!")?
+            .await?;
+
+        ctx.http
+            .update_message(reply.channel_id, reply.id)
+            .content(format!("Pong! {}ms", start.elapsed().as_millis()))?
+            .await?;
+
+        Ok(())
+    }
+    .boxed_local()
+}
+
+fn status<'a>(ctx: &'a Context<'a>, _: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let enabled = |flag: bool| if flag { "on" } else { "off" };
+
+        let embed = EmbedBuilder::new()
+            .color(0x1a7701)?
+            .title(format!("kleinerbot {}", env!("CARGO_PKG_VERSION")))?
+            .field(
+                EmbedFieldBuilder::new("Uptime", format_duration(ctx.started.elapsed()))?.inline(),
+            )
+            .field(EmbedFieldBuilder::new("Web API", enabled(ctx.config.web.is_some()))?.inline())
+            .field(EmbedFieldBuilder::new("MySQL", enabled(ctx.config.mysql.is_some()))?.inline())
+            .field(EmbedFieldBuilder::new("Database", enabled(ctx.db.is_some()))?.inline())
+            .field(
+                EmbedFieldBuilder::new("Logging", enabled(ctx.config.messages_enabled))?.inline(),
+            )
+            .build()?;
+
+        ctx.reply_embed(embed).await
+    }
+    .boxed_local()
+}
+
+fn servers<'a>(ctx: &'a Context<'a>, _: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let servers = ctx.servers.get();
+
+        if servers.is_empty() {
+            return ctx.reply_error("No servers polled yet").await;
+        }
+
+        let mut embed = EmbedBuilder::new().color(0x1a7701)?.title("Servers")?;
+
+        for server in servers {
+            embed = embed.field(
+                EmbedFieldBuilder::new(
+                    server.name,
+                    format!("{} {}/{}", server.map, server.players, server.slots),
+                )?
+                .inline(),
+            );
+        }
+
+        ctx.reply_embed(embed.build()?).await
+    }
+    .boxed_local()
+}
@@ -0,0 +1,138 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use sqlx::any::{AnyConnectOptions, AnyKind, AnyPool, AnyRow};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Executor, Row};
+
+use crate::utils::config::Config;
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    mysql: &'static str,
+    sqlite: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            mysql: include_str!(concat!("../migrations/mysql/", $name, ".sql")),
+            sqlite: include_str!(concat!("../migrations/sqlite/", $name, ".sql")),
+        }
+    };
+}
+
+// Append only, a migration that already ran somewhere is never edited
+const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_moderation"),
+    migration!(2, "0002_signed_ids"),
+    migration!(3, "0003_guild_settings"),
+    migration!(4, "0004_raids"),
+    migration!(5, "0005_starboard"),
+];
+
+const SCHEMA_TABLE: &str = "CREATE TABLE IF NOT EXISTS `kleinerbot_schema` (
+    `version` BIGINT NOT NULL PRIMARY KEY,
+    `name` VARCHAR(64) NOT NULL,
+    `applied_at` BIGINT NOT NULL
+)";
+
+/// Pool for the bot's own tables, connects on first use, None without the database section
+pub fn pool(config: &Config) -> Result<Option<AnyPool>> {
+    let options = match &config.database {
+        Some(database) if database.url.starts_with("sqlite:") => {
+            let sqlite = SqliteConnectOptions::from_str(&database.url)?.create_if_missing(true);
+            AnyConnectOptions::from(sqlite)
+        }
+        Some(database) => AnyConnectOptions::from_str(&database.url)?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(AnyPool::connect_lazy_with(options)))
+}
+
+// Splits a migration file into statements, skipping comment lines
+fn statements(sql: &str) -> Vec<String> {
+    let code: Vec<&str> = sql
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("--"))
+        .collect();
+
+    code.join("\n")
+        .split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The newest migration applied to the database, 0 for a fresh one
+pub async fn version(pool: &AnyPool) -> Result<i64> {
+    pool.execute(SCHEMA_TABLE).await?;
+
+    let row = sqlx::query("SELECT COALESCE(MAX(version), 0) AS version FROM `kleinerbot_schema`")
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.try_get("version")?)
+}
+
+/// Applies every migration the database doesn't have yet, returns how many ran
+pub async fn migrate(pool: &AnyPool) -> Result<usize> {
+    let current = version(pool).await?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+
+    if current > latest {
+        bail!(
+            "Database schema is at version {}, this build only knows up to {}",
+            current,
+            latest
+        );
+    }
+
+    let mut applied = 0;
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        let sql = match pool.any_kind() {
+            AnyKind::MySql => migration.mysql,
+            AnyKind::Sqlite => migration.sqlite,
+        };
+
+        // MySQL commits schema changes right away, so there's no transaction to roll back
+        for statement in statements(sql) {
+            pool.execute(statement.as_str())
+                .await
+                .with_context(|| format!("Migration {} failed", migration.name))?;
+        }
+
+        let applied_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        sqlx::query("INSERT INTO `kleinerbot_schema` (version,name,applied_at) VALUES (?, ?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(applied_at as i64)
+            .execute(pool)
+            .await?;
+
+        info!("Database: Applied migration {}", migration.name);
+        applied += 1;
+    }
+
+    Ok(applied)
+}
+
+/// Ids and timestamps are stored as signed BIGINT, SQLite has no unsigned 64-bit integers
+pub fn get_u64(row: &AnyRow, column: &str) -> Result<u64> {
+    Ok(row.try_get::<i64, _>(column)? as u64)
+}
+
+pub fn get_optional_u64(row: &AnyRow, column: &str) -> Result<Option<u64>> {
+    Ok(row
+        .try_get::<Option<i64>, _>(column)?
+        .map(|value| value as u64))
+}
@@ -0,0 +1,125 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::future::{join_all, select, Either, LocalBoxFuture};
+use futures::stream::StreamExt;
+use log::{info, warn};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::{Cluster, Event};
+
+use crate::supervisor::CancelToken;
+use crate::utils::{config::Config, metrics::Metrics};
+
+/// A feature that reacts to gateway events
+pub trait Module {
+    fn name(&self) -> &'static str;
+
+    /// The cache still holds the state from before the event, so edits and deletes can look up the old message
+    fn on_event<'a>(
+        &'a mut self,
+        cache: &'a InMemoryCache,
+        shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>>;
+
+    /// Takes over a reloaded config, never called while an event is being handled
+    fn reload(&mut self, config: &Config) -> Result<()>;
+}
+
+/// Reads the gateway once for all shards and hands every event to all registered modules
+pub struct EventBus {
+    cache: InMemoryCache,
+    modules: Vec<Box<dyn Module>>,
+    metrics: Metrics,
+}
+
+impl EventBus {
+    pub fn new(metrics: Metrics) -> Self {
+        // Every event type is cached, modules share whatever they need
+        let cache_config = InMemoryCache::builder()
+            .message_cache_size(32768)
+            .build();
+
+        Self {
+            cache: InMemoryCache::from(cache_config),
+            modules: Vec::new(),
+            metrics,
+        }
+    }
+
+    pub fn register(&mut self, module: impl Module + 'static) {
+        info!("Event bus: Registered {}", module.name());
+        self.modules.push(Box::new(module));
+    }
+
+    async fn dispatch(&mut self, shard_id: u64, event: &Event) {
+        let cache = &self.cache;
+        self.metrics.event(format!("{:?}", event.kind()));
+
+        if let Event::Ready(ready) = event {
+            info!("User '{}' is ready on shard {}", ready.user.name, shard_id);
+        }
+
+        // Modules run concurrently, a failing one doesn't affect the others
+        let results = join_all(
+            self.modules
+                .iter_mut()
+                .map(|module| async move { (module.name(), module.on_event(cache, shard_id, event).await) }),
+        )
+        .await;
+
+        for (name, result) in results {
+            self.metrics.module_result(name, &result);
+            result.unwrap_or_else(|err| warn!("{} failed on shard {}: {}", name, shard_id, err));
+        }
+
+        self.cache.update(event);
+    }
+
+    fn reload(&mut self, config: &Config) {
+        for module in self.modules.iter_mut() {
+            module
+                .reload(config)
+                .unwrap_or_else(|err| warn!("{} reload failed: {}", module.name(), err));
+        }
+        info!("Event bus: Config reloaded");
+    }
+
+    // Subscribes right away, call it before the cluster is brought up so Ready isn't missed
+    pub fn run(
+        mut self,
+        cluster: &Cluster,
+        mut reloads: UnboundedReceiver<Arc<Config>>,
+        token: CancelToken,
+    ) -> impl Future<Output = ()> {
+        let cluster = cluster.clone();
+        let mut events = cluster.events();
+
+        // Module state and the cache outlive the stream, only the stream is renewed
+        async move {
+            loop {
+                loop {
+                    let other = select(reloads.select_next_some(), token.cancelled());
+
+                    match select(events.next(), other).await {
+                        Either::Left((Some((shard_id, event)), _)) => {
+                            self.dispatch(shard_id, &event).await
+                        }
+                        Either::Left((None, _)) => break,
+                        Either::Right((Either::Left((config, _)), _)) => self.reload(&config),
+                        Either::Right((Either::Right(_), _)) => return,
+                    }
+                }
+
+                warn!("Event bus: Gateway stream ended, resubscribing");
+                if !token.delay_for(Duration::from_secs(5)).await {
+                    return;
+                }
+                events = cluster.events();
+            }
+        }
+    }
+}
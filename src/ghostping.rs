@@ -0,0 +1,147 @@
+use anyhow::Result;
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::info;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_embed_builder::EmbedBuilder;
+use twilight_gateway::Event;
+use twilight_http::Client as TwilightHttp;
+use twilight_model::id::{ChannelId, RoleId, UserId};
+
+use crate::events::Module;
+use crate::utils::{
+    config::Config,
+    metrics::{CacheMiss, Metrics},
+    snowflake,
+};
+
+// Mentions that disappeared right after they notified someone
+async fn ghost_ping(
+    http: &TwilightHttp,
+    channel_id: ChannelId,
+    author: UserId,
+    users: &[UserId],
+    roles: &[RoleId],
+) -> Result<()> {
+    let mut pinged: Vec<String> = users
+        .iter()
+        .filter(|id| **id != author)
+        .map(|id| format!("<@{}>", id))
+        .collect();
+    pinged.extend(roles.iter().map(|id| format!("<@&{}>", id)));
+
+    if pinged.is_empty() {
+        return Ok(());
+    }
+
+    // Embeds don't notify, so the notice doesn't ping everyone again
+    let embed = EmbedBuilder::new()
+        .color(0x9b59b6)?
+        .title("Ghost ping")?
+        .description(format!("<@{}> pinged {}", author, pinged.join(", ")))?
+        .build()?;
+
+    http.create_message(channel_id).embed(embed)?.await?;
+
+    info!("Ghost ping by {} in {}", author, channel_id);
+
+    Ok(())
+}
+
+/// Reports mentions edited or deleted away in the guilds in ghostping_guilds
+pub struct GhostPings {
+    http: TwilightHttp,
+    config: Config,
+    metrics: Metrics,
+}
+
+impl GhostPings {
+    pub fn new(config: Config, metrics: Metrics) -> Self {
+        Self {
+            http: TwilightHttp::new(&config.discord.token),
+            config,
+            metrics,
+        }
+    }
+
+    async fn event(&self, cache: &InMemoryCache, event: &Event) -> Result<()> {
+        let Self {
+            http,
+            config,
+            metrics,
+        } = self;
+
+        match event {
+            Event::MessageUpdate(msg) => {
+                let window = match msg.guild_id.and_then(|guild_id| config.ghostping_window(guild_id.0)) {
+                    Some(window) => window,
+                    None => return Ok(()),
+                };
+                let mentions = match &msg.mentions {
+                    Some(mentions) if snowflake::age(msg.id.0) <= window => mentions,
+                    _ => return Ok(()),
+                };
+
+                let oldmsg = cache
+                    .message(msg.channel_id, msg.id)
+                    .or_miss(metrics, "GhostPing: Message")?;
+
+                let users: Vec<UserId> = oldmsg
+                    .mentions
+                    .iter()
+                    .filter(|id| !mentions.iter().any(|user| user.id == **id))
+                    .copied()
+                    .collect();
+
+                let roles: Vec<RoleId> = match &msg.mention_roles {
+                    Some(new_roles) => oldmsg
+                        .mention_roles
+                        .iter()
+                        .filter(|id| !new_roles.contains(id))
+                        .copied()
+                        .collect(),
+                    None => Vec::new(),
+                };
+
+                ghost_ping(http, msg.channel_id, oldmsg.author, &users, &roles).await?;
+            }
+            Event::MessageDelete(msg) => {
+                let window = match msg.guild_id.and_then(|guild_id| config.ghostping_window(guild_id.0)) {
+                    Some(window) => window,
+                    None => return Ok(()),
+                };
+                if snowflake::age(msg.id.0) > window {
+                    return Ok(());
+                }
+
+                let oldmsg = cache
+                    .message(msg.channel_id, msg.id)
+                    .or_miss(metrics, "GhostPing: Message")?;
+
+                ghost_ping(http, msg.channel_id, oldmsg.author, &oldmsg.mentions, &oldmsg.mention_roles).await?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl Module for GhostPings {
+    fn name(&self) -> &'static str {
+        "Ghost pings"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        self.event(cache, event).boxed_local()
+    }
+
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.config = config.clone();
+        Ok(())
+    }
+}
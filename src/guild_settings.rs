@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::{info, warn};
+use sqlx::{any::AnyPool, Row};
+use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder};
+use twilight_model::{
+    channel::{Channel, GuildChannel},
+    guild::Permissions,
+    id::{ChannelId, GuildId, RoleId},
+};
+
+use crate::commands::{parse_args, ArgKind, ArgSpec, Args, Command, Commands, Context};
+use crate::utils::config::Config;
+
+// Rows changed by hand or by another instance show up after this long
+const CACHE_TTL: Duration = Duration::from_secs(300);
+// A failed load is retried after this long, until then the fallback is served from the cache
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogCategory {
+    Edits,
+    Deletes,
+    Voice,
+}
+
+impl LogCategory {
+    const ALL: &'static [LogCategory] = &[LogCategory::Edits, LogCategory::Deletes, LogCategory::Voice];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LogCategory::Edits => "edits",
+            LogCategory::Deletes => "deletes",
+            LogCategory::Voice => "voice",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|category| category.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Setting {
+    Prefix,
+    LogChannel,
+    LogCategories,
+    ModerationLogChannel,
+    StarboardThreshold,
+    MuteRole,
+    AutomodLogChannel,
+    RaidAlertChannel,
+    RaidAlertRole,
+}
+
+impl Setting {
+    const ALL: &'static [Setting] = &[
+        Setting::Prefix,
+        Setting::LogChannel,
+        Setting::LogCategories,
+        Setting::ModerationLogChannel,
+        Setting::StarboardThreshold,
+        Setting::MuteRole,
+        Setting::AutomodLogChannel,
+        Setting::RaidAlertChannel,
+        Setting::RaidAlertRole,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Setting::Prefix => "prefix",
+            Setting::LogChannel => "log_channel",
+            Setting::LogCategories => "log_categories",
+            Setting::ModerationLogChannel => "moderation_log_channel",
+            Setting::StarboardThreshold => "starboard_threshold",
+            Setting::MuteRole => "mute_role",
+            Setting::AutomodLogChannel => "automod_log_channel",
+            Setting::RaidAlertChannel => "antiraid_alert_channel",
+            Setting::RaidAlertRole => "antiraid_alert_role",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Setting::Prefix => "Command prefix",
+            Setting::LogChannel => "Channel for message and voice logs",
+            Setting::LogCategories => "What gets logged: edits, deletes, voice or none",
+            Setting::ModerationLogChannel => "Channel for moderation cases",
+            Setting::StarboardThreshold => "Stars needed for the starboard",
+            Setting::MuteRole => "Role given to muted members",
+            Setting::AutomodLogChannel => "Channel for automod triggers",
+            Setting::RaidAlertChannel => "Channel for raid alerts",
+            Setting::RaidAlertRole => "Role pinged by raid alerts",
+        }
+    }
+
+    fn is_channel(self) -> bool {
+        matches!(
+            self,
+            Setting::LogChannel
+                | Setting::ModerationLogChannel
+                | Setting::AutomodLogChannel
+                | Setting::RaidAlertChannel
+        )
+    }
+
+    fn is_role(self) -> bool {
+        self == Setting::MuteRole || self == Setting::RaidAlertRole
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|setting| setting.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Per-guild overrides of config.yaml, unset ones fall back to it
+#[derive(Debug, Clone, Default)]
+pub struct GuildSettings {
+    pub prefix: Option<String>,
+    pub log_channel: Option<u64>,
+    // None logs everything
+    pub log_categories: Option<Vec<LogCategory>>,
+    pub moderation_log_channel: Option<u64>,
+    pub starboard_threshold: Option<u64>,
+    pub mute_role: Option<u64>,
+    pub automod_log_channel: Option<u64>,
+    pub antiraid_alert_channel: Option<u64>,
+    pub antiraid_alert_role: Option<u64>,
+}
+
+impl GuildSettings {
+    pub fn log_channel(&self, config: &Config) -> ChannelId {
+        ChannelId(self.log_channel.unwrap_or(config.logging.channel))
+    }
+
+    pub fn logs(&self, category: LogCategory) -> bool {
+        self.log_categories
+            .as_ref()
+            .map_or(true, |categories| categories.contains(&category))
+    }
+
+    pub fn moderation_log_channel(&self, config: &Config) -> Option<u64> {
+        self.moderation_log_channel.or(config.moderation_log_channel)
+    }
+
+    pub fn mute_role(&self, config: &Config) -> Option<u64> {
+        self.mute_role.or(config.mute_role)
+    }
+
+    pub fn automod_log_channel(&self, config: &Config) -> Option<u64> {
+        self.automod_log_channel.or(config.automod_log_channel)
+    }
+
+    pub fn antiraid_alert_channel(&self, config: &Config) -> Option<u64> {
+        self.antiraid_alert_channel.or(config.antiraid_alert_channel)
+    }
+
+    pub fn antiraid_alert_role(&self, config: &Config) -> Option<u64> {
+        self.antiraid_alert_role.or(config.antiraid_alert_role)
+    }
+
+    // Where a channel or role setting is kept
+    fn id_mut(&mut self, setting: Setting) -> Option<&mut Option<u64>> {
+        match setting {
+            Setting::LogChannel => Some(&mut self.log_channel),
+            Setting::ModerationLogChannel => Some(&mut self.moderation_log_channel),
+            Setting::MuteRole => Some(&mut self.mute_role),
+            Setting::AutomodLogChannel => Some(&mut self.automod_log_channel),
+            Setting::RaidAlertChannel => Some(&mut self.antiraid_alert_channel),
+            Setting::RaidAlertRole => Some(&mut self.antiraid_alert_role),
+            Setting::Prefix | Setting::LogCategories | Setting::StarboardThreshold => None,
+        }
+    }
+
+    // Checks a value from the command or the database, errors are shown to the user
+    fn apply(&mut self, setting: Setting, value: &str) -> Result<(), String> {
+        let value = value.trim();
+
+        match setting {
+            Setting::Prefix => {
+                if value.is_empty() || value.len() > 16 || value.contains(char::is_whitespace) {
+                    return Err("A prefix is 1 to 16 characters without spaces".to_string());
+                }
+                self.prefix = Some(value.to_string());
+            }
+            Setting::LogChannel
+            | Setting::ModerationLogChannel
+            | Setting::MuteRole
+            | Setting::AutomodLogChannel
+            | Setting::RaidAlertChannel
+            | Setting::RaidAlertRole => {
+                let kind = if setting.is_role() { "role" } else { "channel" };
+                let id = value.parse().map_err(|_| format!("`{}` is not a {}", value, kind))?;
+                if let Some(slot) = self.id_mut(setting) {
+                    *slot = Some(id);
+                }
+            }
+            Setting::LogCategories => {
+                let categories = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("none"))
+                    .map(|name| LogCategory::parse(name).ok_or_else(|| format!("`{}` is not a log category", name)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.log_categories = Some(categories);
+            }
+            Setting::StarboardThreshold => {
+                let threshold = value
+                    .parse()
+                    .ok()
+                    .filter(|threshold| *threshold > 0)
+                    .ok_or_else(|| "The threshold must be at least 1".to_string())?;
+                self.starboard_threshold = Some(threshold);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The stored form, None when the setting falls back to config.yaml
+    fn value(&self, setting: Setting) -> Option<String> {
+        match setting {
+            Setting::Prefix => self.prefix.clone(),
+            Setting::LogChannel => self.log_channel.map(|channel| channel.to_string()),
+            Setting::LogCategories => self.log_categories.as_ref().map(|categories| {
+                let names: Vec<&str> = categories.iter().map(|category| category.as_str()).collect();
+                names.join(",")
+            }),
+            Setting::ModerationLogChannel => self.moderation_log_channel.map(|channel| channel.to_string()),
+            Setting::StarboardThreshold => self.starboard_threshold.map(|threshold| threshold.to_string()),
+            Setting::MuteRole => self.mute_role.map(|role| role.to_string()),
+            Setting::AutomodLogChannel => self.automod_log_channel.map(|channel| channel.to_string()),
+            Setting::RaidAlertChannel => self.antiraid_alert_channel.map(|channel| channel.to_string()),
+            Setting::RaidAlertRole => self.antiraid_alert_role.map(|role| role.to_string()),
+        }
+    }
+}
+
+/// Guild settings from the database, cached for CACHE_TTL, clones share the cache
+#[derive(Clone)]
+pub struct SettingsStore {
+    pool: Option<AnyPool>,
+    // Settings and when they have to be loaded again
+    cache: Arc<RwLock<HashMap<GuildId, (Instant, Arc<GuildSettings>)>>>,
+}
+
+impl SettingsStore {
+    pub fn new(pool: Option<AnyPool>) -> Self {
+        Self {
+            pool,
+            cache: Arc::default(),
+        }
+    }
+
+    async fn load(pool: &AnyPool, guild_id: GuildId) -> Result<GuildSettings> {
+        let rows = sqlx::query("SELECT name,value FROM `kleinerbot_guild_settings` WHERE guild_id = ?")
+            .bind(guild_id.0 as i64)
+            .fetch_all(pool)
+            .await?;
+
+        let mut settings = GuildSettings::default();
+
+        for row in rows {
+            let name: String = row.try_get("name")?;
+            let value: String = row.try_get("value")?;
+
+            Setting::parse(&name)
+                .ok_or_else(|| "unknown setting".to_string())
+                .and_then(|setting| settings.apply(setting, &value))
+                .unwrap_or_else(|err| warn!("Guild settings: Ignoring {} in {}: {}", name, guild_id, err));
+        }
+
+        Ok(settings)
+    }
+
+    /// Never fails, without a database or while it's down the guild gets the last known or default settings
+    pub async fn get(&self, guild_id: GuildId) -> Arc<GuildSettings> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Arc::default(),
+        };
+
+        let cached = self.cache.read().unwrap().get(&guild_id).cloned();
+
+        if let Some((expires, settings)) = &cached {
+            if Instant::now() < *expires {
+                return settings.clone();
+            }
+        }
+
+        // Every event of the guild waits for this, so failures are cached too instead of hitting the database each time
+        let (settings, ttl) = match Self::load(pool, guild_id).await {
+            Ok(settings) => (Arc::new(settings), CACHE_TTL),
+            Err(err) => {
+                warn!("Guild settings: Loading {} failed: {}", guild_id, err);
+                (cached.map(|(_, settings)| settings).unwrap_or_default(), RETRY_AFTER)
+            }
+        };
+
+        self.cache
+            .write()
+            .unwrap()
+            .insert(guild_id, (Instant::now() + ttl, settings.clone()));
+        settings
+    }
+
+    // Stores the value, or deletes it to fall back to config.yaml, the next get reloads the guild
+    async fn save(&self, guild_id: GuildId, setting: Setting, value: Option<String>) -> Result<()> {
+        let pool = self.pool.as_ref().context("Guild settings need a database")?;
+
+        // One transaction, so a failed insert doesn't lose the old value
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM `kleinerbot_guild_settings` WHERE guild_id = ? AND name = ?")
+            .bind(guild_id.0 as i64)
+            .bind(setting.name())
+            .execute(&mut tx)
+            .await?;
+
+        if let Some(value) = value {
+            sqlx::query("INSERT INTO `kleinerbot_guild_settings` (guild_id,name,value) VALUES (?, ?, ?)")
+                .bind(guild_id.0 as i64)
+                .bind(setting.name())
+                .bind(value)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        self.cache.write().unwrap().remove(&guild_id);
+
+        Ok(())
+    }
+}
+
+pub fn register(commands: &mut Commands) {
+    commands.register(Command {
+        name: "config",
+        description: "Show this server's settings, change one, or reset it to the default",
+        args: &[
+            ArgSpec::optional("setting", ArgKind::Word),
+            ArgSpec::optional("value|reset", ArgKind::Rest),
+        ],
+        permissions: Permissions::MANAGE_GUILD,
+        handler: config,
+    });
+}
+
+fn show(setting: Setting, value: Option<String>) -> String {
+    match value {
+        Some(value) if setting.is_channel() => format!("<#{}>", value),
+        Some(value) if value.is_empty() => "none".to_string(),
+        Some(value) => format!("`{}`", value),
+        None => "default".to_string(),
+    }
+}
+
+fn config<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let guild_id = ctx
+            .message
+            .guild_id
+            .context("Guild settings outside of a guild")?;
+        let current = ctx.settings.get(guild_id).await;
+
+        let setting = match args.text(0) {
+            Some(name) => match Setting::parse(name) {
+                Some(setting) => setting,
+                None => return ctx.reply_error(format!("Unknown setting `{}`", name)).await,
+            },
+            None => {
+                let mut embed = EmbedBuilder::new().color(0x1a7701)?.title("Server settings")?;
+
+                for setting in Setting::ALL {
+                    embed = embed.field(EmbedFieldBuilder::new(
+                        format!("{}: {}", setting.name(), show(*setting, current.value(*setting))),
+                        setting.description(),
+                    )?);
+                }
+
+                return ctx.reply_embed(embed.build()?).await;
+            }
+        };
+
+        let value = match args.text(1) {
+            Some(value) => value,
+            None => {
+                return ctx
+                    .reply(format!("{} is {}", setting.name(), show(setting, current.value(setting))))
+                    .await
+            }
+        };
+
+        let mut settings = (*current).clone();
+
+        // Mentions become ids the same way channel arguments of other commands do
+        let value = if setting.is_channel() && !value.eq_ignore_ascii_case("reset") {
+            match parse_args(&[ArgSpec::required("channel", ArgKind::Channel)], value) {
+                Ok(args) => args.channel(0).context("Parsed channel missing")?.to_string(),
+                Err(err) => return ctx.reply_error(err).await,
+            }
+        } else if setting.is_role() {
+            let value = value.trim();
+            value
+                .strip_prefix("<@&")
+                .and_then(|id| id.strip_suffix('>'))
+                .unwrap_or(value)
+                .to_string()
+        } else {
+            value.to_string()
+        };
+
+        let stored = if value.eq_ignore_ascii_case("reset") {
+            None
+        } else {
+            if let Err(err) = settings.apply(setting, &value) {
+                return ctx.reply_error(err).await;
+            }
+            settings.value(setting)
+        };
+
+        // Logs must not leak into a channel of another server
+        if let (true, Some(channel)) = (setting.is_channel(), &stored) {
+            let channel = ChannelId(channel.parse()?);
+            let in_guild = match ctx.http.channel(channel).await? {
+                Some(Channel::Guild(GuildChannel::Text(text))) => text.guild_id == Some(guild_id),
+                _ => false,
+            };
+            if !in_guild {
+                return ctx.reply_error("That isn't a text channel of this server").await;
+            }
+        }
+
+        // Pinging or handing out a role of another server would fail on every use
+        if let (true, Some(role)) = (setting.is_role(), &stored) {
+            let role = RoleId(role.parse()?);
+            let in_guild = ctx
+                .http
+                .roles(guild_id)
+                .await?
+                .iter()
+                .any(|guild_role| guild_role.id == role);
+            // The @everyone role shares the guild's id and can't be given or pinged
+            if !in_guild || role.0 == guild_id.0 {
+                return ctx.reply_error("That isn't a role of this server").await;
+            }
+        }
+
+        ctx.settings.save(guild_id, setting, stored.clone()).await?;
+
+        info!(
+            "Guild settings: {} set {} to {:?} in {}",
+            ctx.message.author.id,
+            setting.name(),
+            stored,
+            guild_id
+        );
+
+        ctx.reply(format!("{} is now {}", setting.name(), show(setting, stored)))
+            .await
+    }
+    .boxed_local()
+}
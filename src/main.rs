@@ -2,56 +2,254 @@
 #![feature(drain_filter)]
 #![feature(try_blocks)]
 
+mod antiraid;
+mod automod;
+mod cli;
+mod commands;
+mod db;
+mod events;
+mod ghostping;
+mod guild_settings;
 mod messages;
+mod moderation;
 mod mysql;
+mod relay_feed;
+mod reload;
+mod starboard;
+mod supervisor;
 mod web;
+mod webhooks;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use futures::channel::mpsc;
+use log::{error, info};
 use env_logger::Env;
-use twilight_gateway::{Intents, Shard};
+use twilight_gateway::{cluster::ShardScheme, Cluster, Intents};
 
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
+use std::process;
+use std::time::Duration;
 
 mod utils {
     pub mod config;
+    pub mod metrics;
+    pub mod presence;
+    pub mod relay;
+    pub mod snowflake;
+    pub mod validate;
 }
 
-use actix_rt::signal::ctrl_c;
-
-use crate::utils::config::Config;
+use crate::antiraid::{Antiraid, RaidState};
+use crate::automod::Automod;
+use crate::cli::{Cli, Command};
+use crate::commands::Router;
+use crate::events::EventBus;
+use crate::ghostping::GhostPings;
+use crate::guild_settings::SettingsStore;
+use crate::messages::MessageLog;
+use crate::mysql::ServerList;
+use crate::relay_feed::RelayFeed;
+use crate::starboard::Starboards;
+use crate::supervisor::Supervisor;
+use crate::utils::{
+    config::{Config, SharedConfig},
+    metrics::Metrics,
+    presence::PresenceOverride,
+    relay::Relay,
+    validate,
+};
+use crate::webhooks::Webhooks;
+
+// Tasks get this long to finish after a shutdown signal
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+// Startup failures exit with 1 through main's Err, a shutdown that left tasks running with 2
+const EXIT_UNCLEAN: i32 = 2;
+// check-config found problems, or the command line was wrong
+const EXIT_BAD_CONFIG: i32 = 1;
 
 #[actix_rt::main]
 async fn main() -> Result<()> {
-    // Default log level is debug
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
-    let path = env::args().nth(1).unwrap_or("config.yaml".to_string());
+    // Everything passes the logger itself, logging.level caps it once the config is read
+    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
+    if env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
 
-    let file = File::open(&path).context(format!("Can't read config file {}", &path))?;
-    let reader = BufReader::new(file);
+    let cli = match Cli::parse(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            process::exit(EXIT_BAD_CONFIG);
+        }
+    };
+
+    if let Command::Help = cli.command {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
 
-    let config: Config = serde_yaml::from_reader(reader).context("Config file read failure")?;
+    let path = cli.config_path;
+    let config = Config::load(&path)?;
+    config.logging.apply();
+
+    // Maintenance commands only need the parts of the config they use
+    match cli.command {
+        Command::Run => run(path, config).await,
+        Command::CheckConfig => {
+            let problems = validate::check(&config);
+            for problem in &problems {
+                eprintln!("{}: {}", path, problem);
+            }
+            if !problems.is_empty() {
+                process::exit(EXIT_BAD_CONFIG);
+            }
+            println!("{} is valid", path);
+            Ok(())
+        }
+        Command::RegisterCommands => cli::register_commands(),
+        Command::Send { channel, content } => cli::send(&config, &channel, &content).await,
+        Command::DbMigrate => cli::db_migrate(&config).await,
+        Command::ExportLogs { channel, limit } => cli::export_logs(&config, channel, limit).await,
+        Command::Help => unreachable!(),
+    }
+}
 
-    let intents = Intents::GUILDS | Intents::GUILD_MESSAGES | Intents::GUILD_MEMBERS | Intents::GUILD_VOICE_STATES;
+async fn run(path: String, config: Config) -> Result<()> {
+    let problems = validate::check(&config);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("Config: {}", problem);
+        }
+        bail!("{} problems in {}, see above", problems.len(), path);
+    }
+    let shared = SharedConfig::new(config.clone());
+
+    let intents = Intents::GUILDS
+        | Intents::GUILD_MESSAGES
+        | Intents::GUILD_MESSAGE_REACTIONS
+        | Intents::GUILD_MEMBERS
+        | Intents::GUILD_VOICE_STATES;
+
+    let scheme = match config.discord.shard_count {
+        Some(total) if total > 0 => ShardScheme::Range {
+            from: 0,
+            to: total - 1,
+            total,
+        },
+        _ => ShardScheme::Auto,
+    };
+
+    let cluster = Cluster::builder(&config.discord.token, intents)
+        .shard_scheme(scheme)
+        .build()
+        .await
+        .context("Gateway cluster setup failure")?;
+
+    let metrics = Metrics::default();
+    let mut supervisor = Supervisor::new(metrics.clone());
+
+    // A database that can't be migrated only turns off what needs it
+    let db = match db::pool(&config)? {
+        Some(pool) => match db::migrate(&pool).await {
+            Ok(_) => Some(pool),
+            Err(err) => {
+                error!("Database migration failure, running without the database: {:#}", err);
+                None
+            }
+        },
+        None => None,
+    };
+    let settings = SettingsStore::new(db.clone());
+
+    if let Some(pool) = &db {
+        moderation::spawn(&mut supervisor, shared.clone(), pool.clone(), settings.clone()).await?;
+    }
 
-    let mut shard = Shard::new(&config.discord_token, intents);
-    shard.start().await?;
+    let presence = PresenceOverride::default();
+    let servers = ServerList::default();
+    let relay = Relay::default();
+    let raid = RaidState::load(db.clone()).await;
+
+    let mut bus = EventBus::new(metrics.clone());
+
+    if config.commands_enabled {
+        bus.register(Router::new(
+            config.clone(),
+            servers.clone(),
+            db.clone(),
+            settings.clone(),
+            raid.clone(),
+        ));
+    }
 
-    if config.web_enabled {
-        web::spawn(config.clone()).await?;
+    if config.antiraid_enabled {
+        bus.register(Antiraid::new(config.clone(), settings.clone(), raid));
     }
 
-    if config.mysql_enabled {
-        mysql::spawn(&shard, config.clone()).await?;
+    if !config.automod_rules.is_empty() {
+        bus.register(Automod::new(config.clone(), db.clone(), settings.clone())?);
     }
 
     if config.messages_enabled {
-        messages::spawn(&shard, config.clone()).await?;
+        bus.register(MessageLog::new(config.clone(), settings.clone(), metrics.clone()));
+    }
+
+    if !config.ghostping_guilds.is_empty() {
+        bus.register(GhostPings::new(config.clone(), metrics.clone()));
+    }
+
+    if !config.starboard_guilds.is_empty() {
+        bus.register(Starboards::new(config.clone(), db, settings));
     }
 
-    ctrl_c().await?;
+    if !config.relay_channels.is_empty() {
+        bus.register(RelayFeed::new(&config, relay.clone()));
+    }
+
+    if !config.webhooks.is_empty() {
+        bus.register(Webhooks::new(config.clone()));
+    }
+
+    let (reloads, reloaded) = mpsc::unbounded();
+    let token = supervisor.token();
+    supervisor.track("Event bus", bus.run(&cluster, reloaded, token));
+
+    reload::spawn(&mut supervisor, path, shared, reloads);
+
+    cluster.up().await;
+    info!("Gateway cluster up with {} shards", cluster.shards().len());
+
+    if config.web.is_some() {
+        web::spawn(
+            &mut supervisor,
+            &cluster,
+            config.clone(),
+            presence.clone(),
+            servers.clone(),
+            relay,
+            metrics.clone(),
+        )
+        .await?;
+    }
+
+    if let Some(settings) = &config.mysql {
+        mysql::spawn(&mut supervisor, &cluster, settings, presence, servers, metrics).await?;
+    }
+
+    let signal = supervisor::signal_received().await?;
+    info!("{} received, shutting down", signal);
+
+    let stopped = supervisor.shutdown(SHUTDOWN_GRACE).await;
+
+    // Close code 1000 tells Discord we're gone instead of leaving the sessions to time out
+    cluster.down();
+    info!("Gateway closed");
+
+    log::logger().flush();
+
+    if !stopped {
+        process::exit(EXIT_UNCLEAN);
+    }
 
     Ok(())
 }
@@ -1,21 +1,30 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use actix_rt::Arbiter;
 use actix_web::client::Client;
-use anyhow::{Context, Result};
-use futures::stream::StreamExt;
+use anyhow::Result;
+use futures::future::{FutureExt, LocalBoxFuture};
 use log::{info, warn};
 use twilight_embed_builder::{EmbedAuthorBuilder, EmbedBuilder, EmbedFooterBuilder, ImageSource};
-use twilight_cache_inmemory::{InMemoryCache, EventType};
-use twilight_gateway::{Event, Shard};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
 use twilight_http::Client as TwilightHttp;
-use twilight_model::{channel::GuildChannel,id::{ChannelId, MessageId, UserId},};
-
-use actix_rt::Arbiter;
-
-use crate::utils::config::Config;
-
-const CID_LOG: ChannelId = ChannelId(697846732201000970);
-const MAXFILESIZE: usize = 1000000000000000; // TODO: Get actual size of max file as usize
+use twilight_model::{
+    channel::{Attachment, GuildChannel},
+    id::{GuildId, MessageId, UserId},
+};
+
+use crate::events::Module;
+use crate::guild_settings::{GuildSettings, LogCategory, SettingsStore};
+use crate::utils::{
+    config::Config,
+    metrics::{CacheMiss, Metrics},
+};
+
+// Discord's upload limit without boosts, bigger attachments couldn't be restored anyway
+const MAXFILESIZE: usize = 8 * 1024 * 1024;
+// Messages whose attachments are kept, the oldest is dropped first
+const CACHED_MESSAGES: usize = 256;
 
 struct ImagesData {
     id: MessageId,
@@ -28,7 +37,7 @@ struct Image {
 }
 
 // https://discordapp.com/channels/381880193251409931/700425302936911884/725105267905134612
-fn get_avatar_url(user_id: UserId, avatar_hash: impl Into<String>) -> Result<ImageSource> {
+pub fn get_avatar_url(user_id: UserId, avatar_hash: impl Into<String>) -> Result<ImageSource> {
     let avatar = ImageSource::url(
     format!(
         "https://cdn.discordapp.com/avatars/{}/a_{}.webp?size=256",
@@ -38,288 +47,360 @@ fn get_avatar_url(user_id: UserId, avatar_hash: impl Into<String>) -> Result<Ima
     Ok(avatar)
 }
 
-async fn task(shard: &Shard, config: &Config) -> Result<()> {
-    let cache_config = InMemoryCache::builder()
-        .event_types(
-            EventType::READY
-                | EventType::GUILD_CREATE
-                | EventType::GUILD_UPDATE
-                | EventType::VOICE_STATE_UPDATE
-                | EventType::VOICE_SERVER_UPDATE
-                | EventType::UNAVAILABLE_GUILD
-                | EventType::MEMBER_CHUNK
-                | EventType::MEMBER_ADD
-                | EventType::MEMBER_REMOVE
-                | EventType::MEMBER_UPDATE
-                | EventType::CHANNEL_CREATE
-                | EventType::CHANNEL_UPDATE
-                | EventType::MESSAGE_CREATE
-                | EventType::MESSAGE_DELETE
-                | EventType::MESSAGE_DELETE_BULK
-                | EventType::MESSAGE_UPDATE,
-        )
-        .message_cache_size(32768)
-        .build();
-    let cache = InMemoryCache::from(cache_config);
-
-    let client = Client::default();
-
-    let http = TwilightHttp::new(&config.discord_token);
-
-    let mut cattaches: Vec<ImagesData> = Vec::with_capacity(256);
-
-    let mut events = shard.events();
-
-    while let Some(event) = events.next().await {
-        let result: Result<_> = try {
-            match &event {
-                Event::Ready(ready) => {
-                    info!("User '{}' is ready", ready.user.name);
-                }
-                Event::MessageCreate(msg) => {
-                    if &msg.channel_id == &CID_LOG {
-                        continue;
-                    }
-                    let message = &***msg;
-                    //Is it really possible to have multiple images in one message?
-                    let mut images: Vec<Image> = Vec::with_capacity(16);
-                    for attach in &message.attachments {
-                        let response_res = client.get(&attach.proxy_url).send().await;
-
-                        if response_res.is_err() {
-                            warn!("MessageCreate: Actix web generic failure {}",response_res.unwrap_err());
-                            continue;
-                        }
+// Shared with the spawned downloads
+type Attachments = Arc<Mutex<Vec<ImagesData>>>;
 
-                        let mut response = response_res.unwrap();
+fn cached_bytes(cattaches: &[ImagesData]) -> u64 {
+    cattaches
+        .iter()
+        .flat_map(|data| data.images.iter())
+        .map(|image| image.body.len() as u64)
+        .sum()
+}
 
-                        if !response.status().is_success() {
-                            warn!("MessageCreate: Web request failed {}", response.status());
-                            continue;
-                        }
+// Runs off the event path so slow downloads don't hold up the other modules
+async fn cache_attachments(
+    client: Client,
+    metrics: Metrics,
+    cattaches: Attachments,
+    id: MessageId,
+    attachments: Vec<Attachment>,
+) {
+    //Is it really possible to have multiple images in one message?
+    let mut images: Vec<Image> = Vec::with_capacity(16);
+    for attach in attachments {
+        if attach.size as usize > MAXFILESIZE {
+            info!("MessageCreate: Not caching {}, it's too big", &attach.filename);
+            continue;
+        }
 
-                        match response.body().limit(MAXFILESIZE).await {
-                            Ok(body) => {
-                                images.push(Image {
-                                    name: attach.filename.to_owned(),
-                                    body,
-                                });
-                                info!("MessageCreate: Caching attachment: {}", &attach.filename);
-                            }
-                            Err(c) => warn!(
-                                "MessageCreate: Failed to fetch image: {}\n {}",
-                                &attach.proxy_url, c
-                            ),
-                        }
-                    }
-                    if !images.is_empty() {
-                        if cattaches.len() >= cattaches.capacity() {
-                            info!("MessageCreate: Stack is filled, draining first");
-                            cattaches.drain(0..1);
-                        }
-                        info!(
-                            "Caching attachment {}/{}",
-                            cattaches.len(),
-                            cattaches.capacity()
-                        );
-                        cattaches.push(ImagesData { id: msg.id, images });
-                    }
-                }
-                Event::MessageUpdate(msg) => {
-                    if &msg.channel_id == &CID_LOG {
-                        continue;
-                    }
+        let response_res = client.get(&attach.proxy_url).send().await;
 
-                    let oldmsg = cache
-                        .message(msg.channel_id, msg.id)
-                        .context("MessageUpdate: Message cache miss")?;
-
-                    let gchannel = cache
-                        .guild_channel(oldmsg.channel_id)
-                        .context("MessageUpdate: Channel cache miss")?;
-
-                    match gchannel.as_ref() {
-                        GuildChannel::Text(ref c) => {
-                            let author = msg
-                                .author
-                                .clone()
-                                .context("MessageUpdate: Author cache miss")?;
-
-                            let avatar = &author
-                                .avatar
-                                .to_owned()
-                                .context("MessageUpdate: Avatar cache miss")?;
-
-                            let newcontent = &msg
-                                .content
-                                .to_owned()
-                                .context("MessageUpdate: Content cache miss")?;
-
-                            let timestamp = &msg
-                                .timestamp
-                                .to_owned()
-                                .context("MessageUpdate: Timestamp cache miss")?;
-
-                            let embed = EmbedBuilder::new()
-                                .color(0xffd700)?
-                                .title(format!("at #{}", c.name))?
-                                .author(EmbedAuthorBuilder::new()
-                                .name(&author.name)?
-                                .icon_url(get_avatar_url(author.id, avatar)?)
-                                )
-                                .description(format!("{} -> {}", oldmsg.content, newcontent))?
-                                .timestamp(timestamp)
-                                .footer(EmbedFooterBuilder::new(format!("A:{} | M:{}", author.id, msg.id))?)
-                                .build()?;
-
-                            &http.create_message(CID_LOG).embed(embed)?.await?;
-                        }
-                        _ => {}
-                    }
-                    info!("MessageUpdate: Message logged {}", msg.id);
-                }
-                Event::MessageDelete(msg) => {
-                    if msg.channel_id == CID_LOG {
-                        continue;
-                    }
+        if response_res.is_err() {
+            warn!("MessageCreate: Actix web generic failure {}",response_res.unwrap_err());
+            continue;
+        }
 
-                    let oldmsg = cache
-                        .message(msg.channel_id, msg.id)
-                        .context("MessageDelete: Message cache miss")?;
-
-                    let gchannel = cache
-                        .guild_channel(oldmsg.channel_id)
-                        .context("MessageDelete: Channel cache miss")?;
-
-                    match gchannel.as_ref() {
-                        GuildChannel::Text(ref c) => {
-                            let author = cache
-                                .user(oldmsg.author.clone())
-                                .context("MessageDelete: Author cache miss")?;
-
-                            let avatar = &author
-                                .avatar
-                                .to_owned()
-                                .context("MessageDelete: Avatar cache miss")?;
-
-                            let delcontent = &oldmsg.content;
-                            let timestamp = &oldmsg.timestamp;
-
-                            let embed = EmbedBuilder::new()
-                                .color(0xb90702)?
-                                .title(format!("at #{}", c.name))?
-                                .author(EmbedAuthorBuilder::new()
-                                .name(&author.name)?
-                                .icon_url(get_avatar_url(author.id, avatar)?)
-                                )
-                                .description(if delcontent.len() > 0 {
-                                    delcontent
-                                } else {
-                                    "Attachment only"
-                                })?
-                                .timestamp(timestamp)
-                                .footer(EmbedFooterBuilder::new(format!("A:{} | M:{}", author.id, msg.id))?)
-                                .build()?;
-
-                            http.create_message(CID_LOG).embed(embed)?.await?;
+        let mut response = response_res.unwrap();
 
-                            let image = cattaches.drain_filter(|data| data.id == msg.id).next();
+        if !response.status().is_success() {
+            warn!("MessageCreate: Web request failed {}", response.status());
+            continue;
+        }
 
-                            if !image.is_none() {
-                                let mut message = http.create_message(CID_LOG);
+        match response.body().limit(MAXFILESIZE).await {
+            Ok(body) => {
+                info!("MessageCreate: Caching attachment: {}", &attach.filename);
+                images.push(Image {
+                    name: attach.filename,
+                    body,
+                });
+            }
+            Err(c) => warn!(
+                "MessageCreate: Failed to fetch image: {}\n {}",
+                &attach.proxy_url, c
+            ),
+        }
+    }
+    if !images.is_empty() {
+        let mut cattaches = cattaches.lock().unwrap();
+        if cattaches.len() >= CACHED_MESSAGES {
+            info!("MessageCreate: Stack is filled, draining first");
+            cattaches.drain(0..1);
+        }
+        info!(
+            "Caching attachment {}/{}",
+            cattaches.len(),
+            CACHED_MESSAGES
+        );
+        cattaches.push(ImagesData { id, images });
+        metrics.set_attachment_cache_bytes(cached_bytes(&cattaches));
+    }
+}
 
-                                for image in
-                                    image.context("MessageDelete: Image cache miss")?.images
-                                {
-                                    let name = image.name.clone();
-                                    info!("MessageDelete: Restoring attachment {}", name);
-                                    message = message.attachment(name, image.body.clone());
-                                }
+// DMs have no settings of their own
+async fn guild_settings(settings: &SettingsStore, guild_id: Option<GuildId>) -> Arc<GuildSettings> {
+    match guild_id {
+        Some(guild_id) => settings.get(guild_id).await,
+        None => Arc::default(),
+    }
+}
 
-                                message.await?;
-                            }
-                        }
-                        _ => {}
-                    }
-                    info!("MessageDelete: Message logged {}", msg.id);
+pub struct MessageLog {
+    client: Client,
+    http: TwilightHttp,
+    config: Config,
+    settings: SettingsStore,
+    metrics: Metrics,
+    cattaches: Attachments,
+}
+
+impl MessageLog {
+    pub fn new(config: Config, settings: SettingsStore, metrics: Metrics) -> Self {
+        Self {
+            client: Client::default(),
+            http: TwilightHttp::new(&config.discord.token),
+            cattaches: Arc::new(Mutex::new(Vec::with_capacity(CACHED_MESSAGES))),
+            config,
+            settings,
+            metrics,
+        }
+    }
+
+    async fn event(&mut self, cache: &InMemoryCache, event: &Event) -> Result<()> {
+        let Self {
+            client,
+            http,
+            config,
+            settings,
+            metrics,
+            cattaches,
+        } = self;
+
+        match event {
+            Event::MessageCreate(msg) => {
+                let guild = guild_settings(settings, msg.guild_id).await;
+                if msg.channel_id == guild.log_channel(config) {
+                    return Ok(());
+                }
+                // A message deleted before its download finished loses its attachments, that's rare enough
+                if !msg.attachments.is_empty() {
+                    Arbiter::spawn(cache_attachments(
+                        client.clone(),
+                        metrics.clone(),
+                        cattaches.clone(),
+                        msg.id,
+                        msg.attachments.clone(),
+                    ));
                 }
-                Event::VoiceStateUpdate(vcstate) => {
-                    let mut vcstate = Arc::new((*vcstate.to_owned()).0);
-
-                    let oldvcstate = cache
-                        .voice_state(
-                            vcstate.user_id,
-                            vcstate
-                                .guild_id
-                                .context("VoiceStateUpdate: guild_id cache miss")?,
-                        );
-
-                    let mut color = 0x1a7701;
-                    let mut message = "joined";
-
-                    if oldvcstate.is_some() && vcstate.channel_id.is_none() {
-                        vcstate = oldvcstate.context("VoiceStateUpdate: Voicestate cache miss")?;
-                        color = 0x77011a;
-                        message = "left";
+            }
+            Event::MessageUpdate(msg) => {
+                let guild = guild_settings(settings, msg.guild_id).await;
+                let log_channel = guild.log_channel(config);
+                if msg.channel_id == log_channel {
+                    return Ok(());
+                }
+
+                let oldmsg = cache
+                    .message(msg.channel_id, msg.id)
+                    .or_miss(metrics, "MessageUpdate: Message")?;
+
+                if !guild.logs(LogCategory::Edits) {
+                    return Ok(());
+                }
+
+                let gchannel = cache
+                    .guild_channel(oldmsg.channel_id)
+                    .or_miss(metrics, "MessageUpdate: Channel")?;
+
+                match gchannel.as_ref() {
+                    GuildChannel::Text(ref c) => {
+                        let author = msg
+                            .author
+                            .clone()
+                            .or_miss(metrics, "MessageUpdate: Author")?;
+
+                        let avatar = &author
+                            .avatar
+                            .to_owned()
+                            .or_miss(metrics, "MessageUpdate: Avatar")?;
+
+                        let newcontent = &msg
+                            .content
+                            .to_owned()
+                            .or_miss(metrics, "MessageUpdate: Content")?;
+
+                        let timestamp = &msg
+                            .timestamp
+                            .to_owned()
+                            .or_miss(metrics, "MessageUpdate: Timestamp")?;
+
+                        let embed = EmbedBuilder::new()
+                            .color(0xffd700)?
+                            .title(format!("at #{}", c.name))?
+                            .author(EmbedAuthorBuilder::new()
+                            .name(&author.name)?
+                            .icon_url(get_avatar_url(author.id, avatar)?)
+                            )
+                            .description(format!("{} -> {}", oldmsg.content, newcontent))?
+                            .timestamp(timestamp)
+                            .footer(EmbedFooterBuilder::new(format!("A:{} | M:{}", author.id, msg.id))?)
+                            .build()?;
+
+                        &http.create_message(log_channel).embed(embed)?.await?;
+                        metrics.log_message();
                     }
+                    _ => {}
+                }
+                info!("MessageUpdate: Message logged {}", msg.id);
+            }
+            Event::MessageDelete(msg) => {
+                let guild = guild_settings(settings, msg.guild_id).await;
+                let log_channel = guild.log_channel(config);
+                if msg.channel_id == log_channel {
+                    return Ok(());
+                }
 
-                    let author = cache
-                        .user(vcstate.user_id)
-                        .context("VoiceStateUpdate: Author cache miss")?;
+                let oldmsg = cache
+                    .message(msg.channel_id, msg.id)
+                    .or_miss(metrics, "MessageDelete: Message")?;
 
-                    let avatar = &author
-                        .avatar
-                        .to_owned()
-                        .context("VoiceStateUpdate: Avatar cache miss")?;
+                if !guild.logs(LogCategory::Deletes) {
+                    let mut cattaches = cattaches.lock().unwrap();
+                    cattaches.retain(|data| data.id != msg.id);
+                    metrics.set_attachment_cache_bytes(cached_bytes(&cattaches));
+                    return Ok(());
+                }
 
-                    let gchannel = cache
-                        .guild_channel(
-                            vcstate
-                                .channel_id
-                                .context("VoiceStateUpdate: Channel id cache miss")?,
-                        )
-                        .context("VoiceStateUpdate: Channel cache miss")?;
+                let gchannel = cache
+                    .guild_channel(oldmsg.channel_id)
+                    .or_miss(metrics, "MessageDelete: Channel")?;
 
-                    match gchannel.as_ref() {
-                        GuildChannel::Voice(ref c) => {
-                            
-                            let embed = EmbedBuilder::new()
-                            .color(color)?
+                match gchannel.as_ref() {
+                    GuildChannel::Text(ref c) => {
+                        let author = cache
+                            .user(oldmsg.author.clone())
+                            .or_miss(metrics, "MessageDelete: Author")?;
+
+                        let avatar = &author
+                            .avatar
+                            .to_owned()
+                            .or_miss(metrics, "MessageDelete: Avatar")?;
+
+                        let delcontent = &oldmsg.content;
+                        let timestamp = &oldmsg.timestamp;
+
+                        let embed = EmbedBuilder::new()
+                            .color(0xb90702)?
+                            .title(format!("at #{}", c.name))?
                             .author(EmbedAuthorBuilder::new()
                             .name(&author.name)?
                             .icon_url(get_avatar_url(author.id, avatar)?)
                             )
-                            .description(format!(
-                                "{} {} the voice chat 🔈{}",
-                                author.name, message, c.name
-                            ))?
+                            .description(if delcontent.len() > 0 {
+                                delcontent
+                            } else {
+                                "Attachment only"
+                            })?
+                            .timestamp(timestamp)
+                            .footer(EmbedFooterBuilder::new(format!("A:{} | M:{}", author.id, msg.id))?)
                             .build()?;
 
-                            &http.create_message(CID_LOG).embed(embed)?.await?;
+                        http.create_message(log_channel).embed(embed)?.await?;
+                        metrics.log_message();
+
+                        let image = {
+                            let mut cattaches = cattaches.lock().unwrap();
+                            let image = cattaches.drain_filter(|data| data.id == msg.id).next();
+                            metrics.set_attachment_cache_bytes(cached_bytes(&cattaches));
+                            image
+                        };
+
+                        if !image.is_none() {
+                            let mut message = http.create_message(log_channel);
+
+                            for image in
+                                image.or_miss(metrics, "MessageDelete: Image")?.images
+                            {
+                                let name = image.name.clone();
+                                info!("MessageDelete: Restoring attachment {}", name);
+                                message = message.attachment(name, image.body.clone());
+                            }
+
+                            message.await?;
+                            metrics.log_message();
                         }
-                        _ => {}
                     }
+                    _ => {}
                 }
-                _ => {}
+                info!("MessageDelete: Message logged {}", msg.id);
             }
-        };
-        result.unwrap_or_else(|err| warn!("Discord events queue failed: {}", err));
-        cache.update(&event);
-    }
+            Event::VoiceStateUpdate(vcstate) => {
+                let mut vcstate = Arc::new((*vcstate.to_owned()).0);
 
-    Ok(())
-}
+                let guild_id = vcstate
+                    .guild_id
+                    .or_miss(metrics, "VoiceStateUpdate: guild_id")?;
+
+                let guild = settings.get(guild_id).await;
+                if !guild.logs(LogCategory::Voice) {
+                    return Ok(());
+                }
+                let log_channel = guild.log_channel(config);
 
-pub async fn spawn(shard: &Shard, config: Config) -> Result<()> {
-    let shard1 = shard.clone();
+                let oldvcstate = cache.voice_state(vcstate.user_id, guild_id);
 
-    Arbiter::spawn(async move {
-        loop {
-            task(&shard1, &config)
-                .await
-                .unwrap_or_else(|err| warn!("Discord messages task failed: {}", err));
+                let mut color = 0x1a7701;
+                let mut message = "joined";
+
+                if oldvcstate.is_some() && vcstate.channel_id.is_none() {
+                    vcstate = oldvcstate.or_miss(metrics, "VoiceStateUpdate: Voicestate")?;
+                    color = 0x77011a;
+                    message = "left";
+                }
+
+                let author = cache
+                    .user(vcstate.user_id)
+                    .or_miss(metrics, "VoiceStateUpdate: Author")?;
+
+                let avatar = &author
+                    .avatar
+                    .to_owned()
+                    .or_miss(metrics, "VoiceStateUpdate: Avatar")?;
+
+                let gchannel = cache
+                    .guild_channel(
+                        vcstate
+                            .channel_id
+                            .or_miss(metrics, "VoiceStateUpdate: Channel id")?,
+                    )
+                    .or_miss(metrics, "VoiceStateUpdate: Channel")?;
+
+                match gchannel.as_ref() {
+                    GuildChannel::Voice(ref c) => {
+                        
+                        let embed = EmbedBuilder::new()
+                        .color(color)?
+                        .author(EmbedAuthorBuilder::new()
+                        .name(&author.name)?
+                        .icon_url(get_avatar_url(author.id, avatar)?)
+                        )
+                        .description(format!(
+                            "{} {} the voice chat 🔈{}",
+                            author.name, message, c.name
+                        ))?
+                        .build()?;
+
+                        &http.create_message(log_channel).embed(embed)?.await?;
+                        metrics.log_message();
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
         }
-    });
 
-    Ok(())
+        Ok(())
+    }
+}
+
+impl Module for MessageLog {
+    fn name(&self) -> &'static str {
+        "Messages"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        self.event(cache, event).boxed_local()
+    }
+
+    // Cached attachments survive the reload
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.config = config.clone();
+        Ok(())
+    }
 }
@@ -0,0 +1,849 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+use anyhow::{Context as _, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::{info, warn};
+use sqlx::{any::AnyPool, any::AnyRow, Row};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder, EmbedFooterBuilder};
+use twilight_http::Client as TwilightHttp;
+use twilight_model::{
+    channel::embed::Embed,
+    guild::Permissions,
+    id::{ChannelId, GuildId, MessageId, RoleId, UserId},
+};
+
+use crate::commands::{format_duration, ArgKind, ArgSpec, Args, Command, Commands, Context};
+use crate::db::{get_optional_u64, get_u64};
+use crate::guild_settings::SettingsStore;
+use crate::supervisor::{CancelToken, Supervisor};
+use crate::utils::config::{Config, SharedConfig};
+
+const HISTORY_LIMIT: u64 = 10;
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(15);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Warn,
+    Mute,
+    Kick,
+    Ban,
+    Unmute,
+    Unban,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Warn => "warn",
+            Action::Mute => "mute",
+            Action::Kick => "kick",
+            Action::Ban => "ban",
+            Action::Unmute => "unmute",
+            Action::Unban => "unban",
+        }
+    }
+
+    fn parse(action: &str) -> Option<Action> {
+        match action {
+            "warn" => Some(Action::Warn),
+            "mute" => Some(Action::Mute),
+            "kick" => Some(Action::Kick),
+            "ban" => Some(Action::Ban),
+            "unmute" => Some(Action::Unmute),
+            "unban" => Some(Action::Unban),
+            _ => None,
+        }
+    }
+
+    fn color(self) -> u32 {
+        match self {
+            Action::Warn => 0xffd700,
+            Action::Mute => 0xff8c00,
+            Action::Kick => 0xd2691e,
+            Action::Ban => 0xb90702,
+            Action::Unmute | Action::Unban => 0x1a7701,
+        }
+    }
+}
+
+pub struct Case {
+    pub guild_id: GuildId,
+    pub number: u64,
+    pub action: Action,
+    pub moderator_id: UserId,
+    pub target_id: UserId,
+    pub reason: String,
+    pub duration: Option<Duration>,
+    pub created_at: u64,
+    pub log_message_id: Option<MessageId>,
+}
+
+impl Case {
+    fn from_row(row: &AnyRow) -> Result<Case> {
+        let action: String = row.try_get("action")?;
+
+        Ok(Case {
+            guild_id: GuildId(get_u64(row, "guild_id")?),
+            number: get_u64(row, "number")?,
+            action: Action::parse(&action).context("Unknown case action")?,
+            moderator_id: UserId(get_u64(row, "moderator_id")?),
+            target_id: UserId(get_u64(row, "target_id")?),
+            reason: row.try_get("reason")?,
+            duration: get_optional_u64(row, "duration")?.map(Duration::from_secs),
+            created_at: get_u64(row, "created_at")?,
+            log_message_id: get_optional_u64(row, "log_message_id")?.map(MessageId),
+        })
+    }
+
+    fn embed(&self) -> Result<Embed> {
+        let mut embed = EmbedBuilder::new()
+            .color(self.action.color())?
+            .title(format!("Case #{} | {}", self.number, self.action.as_str()))?
+            .field(
+                EmbedFieldBuilder::new(
+                    "User",
+                    format!("<@{}> ({})", self.target_id, self.target_id),
+                )?
+                .inline(),
+            )
+            .field(
+                EmbedFieldBuilder::new("Moderator", format!("<@{}>", self.moderator_id))?.inline(),
+            );
+
+        if let Some(duration) = self.duration {
+            embed = embed
+                .field(EmbedFieldBuilder::new("Duration", format_duration(duration))?.inline());
+        }
+
+        Ok(embed
+            .field(EmbedFieldBuilder::new("Reason", &self.reason)?)
+            .footer(EmbedFooterBuilder::new(format!(
+                "A:{} | T:{}",
+                self.moderator_id, self.created_at
+            ))?)
+            .build()?)
+    }
+}
+
+const CASE_COLUMNS: &str =
+    "guild_id,number,action,moderator_id,target_id,reason,duration,created_at,log_message_id";
+
+/// How often a case number is reallocated after losing a race for it
+const CASE_NUMBER_ATTEMPTS: usize = 5;
+
+/// Whether the query failed because the primary key is already taken
+fn is_duplicate_key(err: &sqlx::Error) -> bool {
+    match err {
+        // MySQL reports the SQLSTATE, SQLite its extended result code
+        sqlx::Error::Database(err) => matches!(
+            err.code().as_deref(),
+            Some("23000") | Some("1555") | Some("2067")
+        ),
+        _ => false,
+    }
+}
+
+async fn create_case(
+    pool: &AnyPool,
+    guild_id: GuildId,
+    action: Action,
+    moderator_id: UserId,
+    target_id: UserId,
+    reason: &str,
+    duration: Option<Duration>,
+) -> Result<Case> {
+    let created_at = now();
+    let mut attempt = 0;
+
+    // The number is taken from the highest existing case, so two cases created at the
+    // same time can pick the same one. The primary key rejects the loser, which retries.
+    let number = loop {
+        attempt += 1;
+
+        let row = sqlx::query(
+            "SELECT COALESCE(MAX(number), 0) + 1 AS next FROM `kleinerbot_cases` WHERE guild_id = ?",
+        )
+        .bind(guild_id.0 as i64)
+        .fetch_one(pool)
+        .await?;
+        let number = get_u64(&row, "next")?;
+
+        let inserted = sqlx::query(
+            "INSERT INTO `kleinerbot_cases` (guild_id,number,action,moderator_id,target_id,reason,duration,created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(guild_id.0 as i64)
+        .bind(number as i64)
+        .bind(action.as_str())
+        .bind(moderator_id.0 as i64)
+        .bind(target_id.0 as i64)
+        .bind(reason)
+        .bind(duration.map(|duration| duration.as_secs() as i64))
+        .bind(created_at as i64)
+        .execute(pool)
+        .await;
+
+        match inserted {
+            Ok(_) => break number,
+            Err(err) if is_duplicate_key(&err) && attempt < CASE_NUMBER_ATTEMPTS => {
+                warn!(
+                    "Case #{} in {} was taken concurrently, retrying",
+                    number, guild_id
+                );
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    Ok(Case {
+        guild_id,
+        number,
+        action,
+        moderator_id,
+        target_id,
+        reason: reason.to_string(),
+        duration,
+        created_at,
+        log_message_id: None,
+    })
+}
+
+async fn delete_case(pool: &AnyPool, guild_id: GuildId, number: u64) -> Result<()> {
+    sqlx::query("DELETE FROM `kleinerbot_cases` WHERE guild_id = ? AND number = ?")
+        .bind(guild_id.0 as i64)
+        .bind(number as i64)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn fetch_case(pool: &AnyPool, guild_id: GuildId, number: u64) -> Result<Option<Case>> {
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM `kleinerbot_cases` WHERE guild_id = ? AND number = ?",
+        CASE_COLUMNS
+    ))
+    .bind(guild_id.0 as i64)
+    .bind(number as i64)
+    .fetch_optional(pool)
+    .await?;
+
+    row.as_ref().map(Case::from_row).transpose()
+}
+
+async fn fetch_history(
+    pool: &AnyPool,
+    guild_id: GuildId,
+    target_id: UserId,
+) -> Result<Vec<Case>> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM `kleinerbot_cases` WHERE guild_id = ? AND target_id = ? ORDER BY number DESC LIMIT ?",
+        CASE_COLUMNS
+    ))
+    .bind(guild_id.0 as i64)
+    .bind(target_id.0 as i64)
+    .bind(HISTORY_LIMIT as i64)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter().map(Case::from_row).collect()
+}
+
+// Posts the case to the log channel, or refreshes the existing post
+async fn log_case(
+    http: &TwilightHttp,
+    pool: &AnyPool,
+    channel: Option<u64>,
+    case: &mut Case,
+) -> Result<()> {
+    let channel = match channel {
+        Some(channel) => ChannelId(channel),
+        None => return Ok(()),
+    };
+
+    match case.log_message_id {
+        Some(message_id) => {
+            http.update_message(channel, message_id)
+                .embed(case.embed()?)?
+                .await?;
+        }
+        None => {
+            let message = http.create_message(channel).embed(case.embed()?)?.await?;
+
+            sqlx::query("UPDATE `kleinerbot_cases` SET log_message_id = ? WHERE guild_id = ? AND number = ?")
+                .bind(message.id.0 as i64)
+                .bind(case.guild_id.0 as i64)
+                .bind(case.number as i64)
+                .execute(pool)
+                .await?;
+
+            case.log_message_id = Some(message.id);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn register(commands: &mut Commands) {
+    const TARGET: ArgSpec = ArgSpec::required("user", ArgKind::User);
+    const DURATION: ArgSpec = ArgSpec::optional("duration", ArgKind::Duration);
+    const REASON: ArgSpec = ArgSpec::optional("reason", ArgKind::Rest);
+
+    commands.register(Command {
+        name: "warn",
+        description: "Warn a member",
+        args: &[TARGET, REASON],
+        permissions: Permissions::KICK_MEMBERS,
+        handler: warn,
+    });
+    commands.register(Command {
+        name: "mute",
+        description: "Give a member the mute role, optionally for a while",
+        args: &[TARGET, DURATION, REASON],
+        permissions: Permissions::MANAGE_ROLES,
+        handler: mute,
+    });
+    commands.register(Command {
+        name: "unmute",
+        description: "Take the mute role away",
+        args: &[TARGET, REASON],
+        permissions: Permissions::MANAGE_ROLES,
+        handler: unmute,
+    });
+    commands.register(Command {
+        name: "kick",
+        description: "Kick a member",
+        args: &[TARGET, REASON],
+        permissions: Permissions::KICK_MEMBERS,
+        handler: kick,
+    });
+    commands.register(Command {
+        name: "ban",
+        description: "Ban a user, optionally for a while",
+        args: &[TARGET, DURATION, REASON],
+        permissions: Permissions::BAN_MEMBERS,
+        handler: ban,
+    });
+    commands.register(Command {
+        name: "unban",
+        description: "Lift a ban",
+        args: &[TARGET, REASON],
+        permissions: Permissions::BAN_MEMBERS,
+        handler: unban,
+    });
+    commands.register(Command {
+        name: "case",
+        description: "Show a moderation case",
+        args: &[ArgSpec::required("number", ArgKind::Integer)],
+        permissions: Permissions::KICK_MEMBERS,
+        handler: case,
+    });
+    commands.register(Command {
+        name: "history",
+        description: "List the latest cases of a user",
+        args: &[TARGET],
+        permissions: Permissions::KICK_MEMBERS,
+        handler: history,
+    });
+    commands.register(Command {
+        name: "reason",
+        description: "Change the reason of a case",
+        args: &[
+            ArgSpec::required("number", ArgKind::Integer),
+            ArgSpec::required("reason", ArgKind::Rest),
+        ],
+        permissions: Permissions::KICK_MEMBERS,
+        handler: reason,
+    });
+}
+
+// Carries out the action against Discord
+async fn apply(
+    http: &TwilightHttp,
+    mute_role: Option<u64>,
+    guild_id: GuildId,
+    target_id: UserId,
+    action: Action,
+    reason: &str,
+) -> Result<()> {
+    match action {
+        Action::Warn => {
+            // Members with closed DMs still get the case
+            let dm: Result<()> = try {
+                let channel = http.create_private_channel(target_id).await?;
+                http.create_message(channel.id)
+                    .content(format!("You have been warned: {}", reason))?
+                    .await?;
+            };
+            dm.unwrap_or_else(|err| warn!("Moderation: Can't DM {}: {}", target_id, err));
+        }
+        Action::Mute | Action::Unmute => {
+            let role = RoleId(mute_role.context("mute_role is not configured")?);
+            if action == Action::Mute {
+                http.add_guild_member_role(guild_id, target_id, role)
+                    .await?;
+            } else {
+                http.remove_guild_member_role(guild_id, target_id, role)
+                    .await?;
+            }
+        }
+        Action::Kick => {
+            http.remove_guild_member(guild_id, target_id).await?;
+        }
+        Action::Ban => {
+            http.create_ban(guild_id, target_id).reason(reason)?.await?;
+        }
+        Action::Unban => {
+            http.delete_ban(guild_id, target_id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Drops a pending expiry, the punishment was lifted or replaced
+async fn clear_expiry(
+    pool: &AnyPool,
+    guild_id: GuildId,
+    target_id: UserId,
+    action: Action,
+) -> Result<()> {
+    sqlx::query(
+        "DELETE FROM `kleinerbot_punishments` WHERE guild_id = ? AND target_id = ? AND action = ?",
+    )
+    .bind(guild_id.0 as i64)
+    .bind(target_id.0 as i64)
+    .bind(action.as_str())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Everything needed to punish outside of a command
+pub struct Enforcer<'a> {
+    pub http: &'a TwilightHttp,
+    pub pool: &'a AnyPool,
+    pub config: &'a Config,
+    pub settings: &'a SettingsStore,
+}
+
+impl<'a> Enforcer<'a> {
+    /// Carries out the action, records its case and posts it to the log channel
+    pub async fn enforce(
+        &self,
+        guild_id: GuildId,
+        moderator_id: UserId,
+        target_id: UserId,
+        action: Action,
+        duration: Option<Duration>,
+        reason: &str,
+    ) -> Result<Case> {
+        let Enforcer {
+            http,
+            pool,
+            config,
+            settings,
+        } = *self;
+
+        let guild_settings = settings.get(guild_id).await;
+        let mute_role = guild_settings.mute_role(config);
+
+        // Record the case first so an action taken on Discord never goes unrecorded, and
+        // take it back out if Discord refuses the action
+        let mut case = create_case(
+            pool,
+            guild_id,
+            action,
+            moderator_id,
+            target_id,
+            reason,
+            duration,
+        )
+        .await?;
+
+        if let Err(err) = apply(http, mute_role, guild_id, target_id, action, reason).await {
+            if let Err(delete_err) = delete_case(pool, guild_id, case.number).await {
+                warn!(
+                    "Failed to remove case #{} in {} after its action failed: {:#}",
+                    case.number, guild_id, delete_err
+                );
+            }
+            return Err(err);
+        }
+
+        match action {
+            Action::Mute | Action::Ban => {
+                clear_expiry(pool, guild_id, target_id, action).await?;
+
+                if let Some(duration) = duration {
+                    sqlx::query(
+                        "INSERT INTO `kleinerbot_punishments` (guild_id,target_id,action,case_number,expires_at)
+                        VALUES (?, ?, ?, ?, ?)",
+                    )
+                    .bind(guild_id.0 as i64)
+                    .bind(target_id.0 as i64)
+                    .bind(action.as_str())
+                    .bind(case.number as i64)
+                    .bind((case.created_at + duration.as_secs()) as i64)
+                    .execute(pool)
+                    .await?;
+                }
+            }
+            Action::Unmute => clear_expiry(pool, guild_id, target_id, Action::Mute).await?,
+            Action::Unban => clear_expiry(pool, guild_id, target_id, Action::Ban).await?,
+            Action::Warn | Action::Kick => {}
+        }
+
+        info!(
+            "Moderation: Case #{} {} {} by {}",
+            case.number,
+            action.as_str(),
+            target_id,
+            moderator_id
+        );
+
+        let channel = guild_settings.moderation_log_channel(config);
+        log_case(http, pool, channel, &mut case).await?;
+
+        Ok(case)
+    }
+}
+
+// Members that aren't cached, like banned users, have no roles
+fn member_roles(cache: &InMemoryCache, guild_id: GuildId, user_id: UserId) -> Vec<RoleId> {
+    cache
+        .member(guild_id, user_id)
+        .map(|member| member.roles.clone())
+        .unwrap_or_default()
+}
+
+// Position of the highest role, @everyone is 0
+fn top_position(cache: &InMemoryCache, roles: &[RoleId]) -> i64 {
+    roles
+        .iter()
+        .filter_map(|role| cache.role(*role))
+        .map(|role| role.position)
+        .max()
+        .unwrap_or(0)
+}
+
+// Shared by all punishment commands
+async fn punish(
+    ctx: &Context<'_>,
+    action: Action,
+    target_id: UserId,
+    duration: Option<Duration>,
+    reason: Option<&str>,
+) -> Result<()> {
+    let pool = ctx.db.context("Moderation needs a database")?;
+    let guild_id = ctx
+        .message
+        .guild_id
+        .context("Moderation outside of a guild")?;
+    let reason = reason.unwrap_or("No reason given");
+
+    if target_id == ctx.message.author.id {
+        return ctx.reply_error("You can't punish yourself").await;
+    }
+
+    // The bot's role is usually above everyone, so Discord's own hierarchy check doesn't protect anyone
+    let guild = match ctx.cache.guild(guild_id) {
+        Some(guild) => guild,
+        None => {
+            return ctx
+                .reply_error("This server isn't cached yet, try again in a moment")
+                .await
+        }
+    };
+
+    if guild.owner_id == target_id {
+        return ctx.reply_error("You can't punish the server owner").await;
+    }
+
+    if guild.owner_id != ctx.message.author.id {
+        let invoker_roles = match &ctx.message.member {
+            Some(member) => member.roles.clone(),
+            None => member_roles(ctx.cache, guild_id, ctx.message.author.id),
+        };
+        let target_roles = member_roles(ctx.cache, guild_id, target_id);
+
+        if top_position(ctx.cache, &target_roles) >= top_position(ctx.cache, &invoker_roles) {
+            return ctx
+                .reply_error("You can't punish members with a role at or above your highest")
+                .await;
+        }
+    }
+
+    let enforcer = Enforcer {
+        http: ctx.http,
+        pool,
+        config: ctx.config,
+        settings: ctx.settings,
+    };
+
+    let case = enforcer
+        .enforce(
+            guild_id,
+            ctx.message.author.id,
+            target_id,
+            action,
+            duration,
+            reason,
+        )
+        .await?;
+
+    ctx.reply_embed(case.embed()?).await
+}
+
+fn warn<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let target_id = args.user(0).context("Missing user")?;
+        punish(ctx, Action::Warn, target_id, None, args.text(1)).await
+    }
+    .boxed_local()
+}
+
+fn mute<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let target_id = args.user(0).context("Missing user")?;
+        punish(ctx, Action::Mute, target_id, args.duration(1), args.text(2)).await
+    }
+    .boxed_local()
+}
+
+fn unmute<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let target_id = args.user(0).context("Missing user")?;
+        punish(ctx, Action::Unmute, target_id, None, args.text(1)).await
+    }
+    .boxed_local()
+}
+
+fn kick<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let target_id = args.user(0).context("Missing user")?;
+        punish(ctx, Action::Kick, target_id, None, args.text(1)).await
+    }
+    .boxed_local()
+}
+
+fn ban<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let target_id = args.user(0).context("Missing user")?;
+        punish(ctx, Action::Ban, target_id, args.duration(1), args.text(2)).await
+    }
+    .boxed_local()
+}
+
+fn unban<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let target_id = args.user(0).context("Missing user")?;
+        punish(ctx, Action::Unban, target_id, None, args.text(1)).await
+    }
+    .boxed_local()
+}
+
+fn case<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let pool = ctx.db.context("Moderation needs a database")?;
+        let guild_id = ctx
+            .message
+            .guild_id
+            .context("Moderation outside of a guild")?;
+        let number = args.integer(0).context("Missing number")?;
+
+        match fetch_case(pool, guild_id, number as u64).await? {
+            Some(case) => ctx.reply_embed(case.embed()?).await,
+            None => {
+                ctx.reply_error(format!("Case #{} doesn't exist", number))
+                    .await
+            }
+        }
+    }
+    .boxed_local()
+}
+
+fn history<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let pool = ctx.db.context("Moderation needs a database")?;
+        let guild_id = ctx
+            .message
+            .guild_id
+            .context("Moderation outside of a guild")?;
+        let target_id = args.user(0).context("Missing user")?;
+
+        let cases = fetch_history(pool, guild_id, target_id).await?;
+
+        if cases.is_empty() {
+            return ctx
+                .reply_error(format!("<@{}> has no cases", target_id))
+                .await;
+        }
+
+        let mut description = String::new();
+        for case in &cases {
+            description += &format!(
+                "**#{}** {} by <@{}>: {}\n",
+                case.number,
+                case.action.as_str(),
+                case.moderator_id,
+                case.reason
+            );
+        }
+
+        let embed = EmbedBuilder::new()
+            .color(0xffd700)?
+            .title(format!("Latest cases of {}", target_id))?
+            .description(description)?
+            .build()?;
+
+        ctx.reply_embed(embed).await
+    }
+    .boxed_local()
+}
+
+fn reason<'a>(ctx: &'a Context<'a>, args: Args) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        let pool = ctx.db.context("Moderation needs a database")?;
+        let guild_id = ctx
+            .message
+            .guild_id
+            .context("Moderation outside of a guild")?;
+        let number = args.integer(0).context("Missing number")? as u64;
+        let reason = args.text(1).context("Missing reason")?;
+
+        let mut case = match fetch_case(pool, guild_id, number).await? {
+            Some(case) => case,
+            None => {
+                return ctx
+                    .reply_error(format!("Case #{} doesn't exist", number))
+                    .await
+            }
+        };
+
+        sqlx::query("UPDATE `kleinerbot_cases` SET reason = ? WHERE guild_id = ? AND number = ?")
+            .bind(reason)
+            .bind(guild_id.0 as i64)
+            .bind(number as i64)
+            .execute(pool)
+            .await?;
+
+        case.reason = reason.to_string();
+
+        let channel = ctx.settings.get(guild_id).await.moderation_log_channel(ctx.config);
+        log_case(ctx.http, pool, channel, &mut case).await?;
+
+        ctx.reply_embed(case.embed()?).await
+    }
+    .boxed_local()
+}
+
+// Unknown Ban, Unknown Member and the like
+fn is_not_found(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<twilight_http::Error>() {
+        Some(twilight_http::Error::Response { status, .. }) => status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+// Lifts every punishment whose time is up, failed ones are retried next tick
+async fn lift_expired(
+    http: &TwilightHttp,
+    pool: &AnyPool,
+    config: &Config,
+    settings: &SettingsStore,
+    bot_id: UserId,
+) -> Result<()> {
+    let enforcer = Enforcer {
+        http,
+        pool,
+        config,
+        settings,
+    };
+
+    let rows = sqlx::query(
+        "SELECT guild_id,target_id,action,case_number FROM `kleinerbot_punishments` WHERE expires_at <= ?",
+    )
+    .bind(now() as i64)
+    .fetch_all(pool)
+    .await?;
+
+    for row in rows {
+        let result: Result<()> = try {
+            let guild_id = GuildId(get_u64(&row, "guild_id")?);
+            let target_id = UserId(get_u64(&row, "target_id")?);
+            let number = get_u64(&row, "case_number")?;
+            let action: String = row.try_get("action")?;
+            let punished = Action::parse(&action).context("Unknown punishment action")?;
+
+            let lift = match punished {
+                Action::Mute => Action::Unmute,
+                _ => Action::Unban,
+            };
+
+            let reason = format!("Case #{} expired", number);
+
+            // The lift clears the pending expiry on success
+            match enforcer
+                .enforce(guild_id, bot_id, target_id, lift, None, &reason)
+                .await
+            {
+                Ok(_) => {}
+                // Unbanned by hand or the muted member left, there's nothing left to lift
+                Err(err) if is_not_found(&err) => {
+                    info!(
+                        "Moderation: Case #{} of {} was already lifted",
+                        number, target_id
+                    );
+                    clear_expiry(pool, guild_id, target_id, punished).await?;
+                }
+                Err(err) => Err(err)?,
+            }
+        };
+        result.unwrap_or_else(|err| warn!("Moderation: Lifting punishment failed: {}", err));
+    }
+
+    Ok(())
+}
+
+async fn scheduler(
+    config: &SharedConfig,
+    pool: &AnyPool,
+    settings: &SettingsStore,
+    token: &CancelToken,
+) -> Result<()> {
+    let http = TwilightHttp::new(&config.get().discord.token);
+    let bot_id = http.current_user().await?.id;
+
+    loop {
+        // Read every round so reloaded log channels and mute roles apply
+        lift_expired(&http, pool, &config.get(), settings, bot_id).await?;
+        if !token.delay_for(SCHEDULER_INTERVAL).await {
+            return Ok(());
+        }
+    }
+}
+
+pub async fn spawn(
+    supervisor: &mut Supervisor,
+    config: SharedConfig,
+    pool: AnyPool,
+    settings: SettingsStore,
+) -> Result<()> {
+    supervisor.spawn("Moderation scheduler", move |token| {
+        let (config, pool, settings) = (config.clone(), pool.clone(), settings.clone());
+
+        async move { scheduler(&config, &pool, &settings, &token).await }
+    });
+
+    Ok(())
+}
@@ -1,66 +1,89 @@
 use anyhow::Result;
 use core::time::Duration;
 use log::{info, warn};
-use twilight_gateway::Shard;
-use twilight_model::gateway::{payload::UpdateStatus,presence::{Activity, ActivityType, Status}};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use twilight_gateway::Cluster;
+use twilight_model::gateway::{payload::UpdateStatus,presence::Status};
 
 use sqlx::{Row, mysql::{MySqlConnectOptions, MySqlPool}};
 
-use actix_rt::Arbiter;
-
-use crate::utils::config::Config;
+use crate::supervisor::{CancelToken, Supervisor};
+use crate::utils::{config::Mysql, metrics::Metrics, presence::{self, PresenceOverride, ACTIVITY_TEMPLATE}};
 
 const IDNAMES: [&str; 3] = ["UNK", "ZS", "TTT"];
 //i am too lazy to create ID names in db
 
-const ACTIVITY_TEMPLATE: Activity = Activity {
-    application_id: None,
-    assets: None,
-    created_at: None,
-    details: None,
-    flags: None,
-    id: None,
-    instance: None,
-    kind: ActivityType::Playing,
-    name: String::new(),
-    emoji: None,
-    party: None,
-    secrets: None,
-    state: None,
-    timestamps: None,
-    url: None,
-};
-
-struct ServerData {
-    id: i32,
-    players: i32,
-    slots: i32,
-    map: String,
+#[derive(Serialize, Clone, Debug)]
+pub struct ServerData {
+    pub id: i32,
+    pub name: &'static str,
+    pub players: i32,
+    pub slots: i32,
+    pub map: String,
 }
 
-async fn task(shard: &Shard, opts: &MySqlConnectOptions) -> Result<()> {
+/// Last polled `gex_servers` rows, shared with the web API
+#[derive(Clone, Default)]
+pub struct ServerList(Arc<Mutex<Vec<ServerData>>>);
+
+impl ServerList {
+    pub fn get(&self) -> Vec<ServerData> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, servers: Vec<ServerData>) {
+        *self.0.lock().unwrap() = servers;
+    }
+}
+
+async fn task(cluster: &Cluster, opts: &MySqlConnectOptions, presence: &PresenceOverride, servers: &ServerList, metrics: &Metrics, token: &CancelToken) -> Result<()> {
     let mut lastid: usize = 0;
 
     let fifteen_secs = Duration::new(15, 0);
 
     loop {
-        let pool = MySqlPool::connect_with(opts.clone()).await?;
-
-        let query = sqlx::query("SELECT id,players,slots,map FROM `gex_servers` WHERE id < 100 ORDER BY id")
-        .fetch_all(&pool)
-        .await?;
-
-        let mut sdata = vec!();
-        for data in query.into_iter() {
-            sdata.push(ServerData {
-                id: data.try_get("id")?,
-                players: data.try_get("players")?,
-                slots: data.try_get("slots")?,
-                map: data.try_get("map")?,
-            });
+        let polled: Result<Vec<ServerData>> = try {
+            let pool = MySqlPool::connect_with(opts.clone()).await?;
+
+            let query = sqlx::query("SELECT id,players,slots,map FROM `gex_servers` WHERE id < 100 ORDER BY id")
+            .fetch_all(&pool)
+            .await?;
+
+            let mut sdata = vec!();
+            for data in query.into_iter() {
+                let id: i32 = data.try_get("id")?;
+                sdata.push(ServerData {
+                    id,
+                    name: IDNAMES.get(id as usize).copied().unwrap_or(IDNAMES[0]),
+                    players: data.try_get("players")?,
+                    slots: data.try_get("slots")?,
+                    map: data.try_get("map")?,
+                });
+            }
+            sdata
+        };
+
+        metrics.mysql_poll(polled.is_ok());
+        let sdata = polled?;
+
+        servers.set(sdata.clone());
+
+        // The table can shrink between polls
+        if lastid >= sdata.len() {
+            lastid = 0;
         }
 
-        let data: &ServerData = &sdata[lastid];
+        let data: &ServerData = match sdata.get(lastid) {
+            Some(data) => data,
+            None => {
+                warn!("No game servers in gex_servers to show");
+                if !token.delay_for(fifteen_secs).await {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
         let mut mapname = data.map.clone();
         mapname.truncate(14);
 
@@ -68,45 +91,51 @@ async fn task(shard: &Shard, opts: &MySqlConnectOptions) -> Result<()> {
 
         activity.name = format!(
             "{}|{}|{}/{}",
-            IDNAMES[data.id as usize], mapname, data.players, data.slots
+            data.name, mapname, data.players, data.slots
         )
         .to_owned();
 
-        shard
-            .command(&UpdateStatus::new(vec!(activity), false, None, Status::Online))
-            .await?;
+        // Web API holds the presence for now
+        if !presence.is_active() {
+            presence::update(cluster, &UpdateStatus::new(vec!(activity), false, None, Status::Online)).await?;
+        }
 
         lastid += 1;
         if lastid == sdata.len() {
             lastid = 0
         }
 
-        actix_rt::time::delay_for(fifteen_secs).await;
+        if !token.delay_for(fifteen_secs).await {
+            return Ok(());
+        }
     }
 }
 
-pub async fn spawn(shard: &Shard, config: Config) -> Result<()> {
+pub fn connect_options(mysql: &Mysql) -> MySqlConnectOptions {
+    MySqlConnectOptions::new()
+    .host(&mysql.hostname)
+    .port(mysql.port.to_owned())
+    .username(&mysql.user)
+    .password(&mysql.password)
+    .database(&mysql.dbname)
+}
+
+pub async fn spawn(supervisor: &mut Supervisor, cluster: &Cluster, mysql: &Mysql, presence: PresenceOverride, servers: ServerList, metrics: Metrics) -> Result<()> {
 
-    let opts = MySqlConnectOptions::new()
-    .host(&config.mysql_hostname)
-    .port(config.mysql_port.to_owned())
-    .username(&config.mysql_user)
-    .password(&config.mysql_password)
-    .database(&config.mysql_dbname);
+    let opts = connect_options(mysql);
 
     info!(
         "Connecting to mysql {}:{}",
-        &config.mysql_hostname, &config.mysql_port
+        &mysql.hostname, &mysql.port
     );
 
-    let shard1 = shard.clone();
+    let cluster = cluster.clone();
 
-    Arbiter::spawn(async move {
-        loop {
-            task(&shard1, &opts)
-                .await
-                .unwrap_or_else(|err| warn!("MySQL task failed: {}", err));
-        }
+    supervisor.spawn("MySQL", move |token| {
+        let (cluster, opts, presence, servers, metrics) =
+            (cluster.clone(), opts.clone(), presence.clone(), servers.clone(), metrics.clone());
+
+        async move { task(&cluster, &opts, &presence, &servers, &metrics, &token).await }
     });
 
     Ok(())
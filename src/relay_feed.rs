@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use futures::future::{FutureExt, LocalBoxFuture};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+
+use crate::events::Module;
+use crate::utils::{config::Config, relay::Relay};
+
+/// Queues messages of the relay_channels for game servers
+pub struct RelayFeed {
+    relay: Relay,
+    // Channel id to its web.channels alias
+    channels: HashMap<u64, String>,
+}
+
+impl RelayFeed {
+    pub fn new(config: &Config, relay: Relay) -> Self {
+        Self {
+            relay,
+            channels: config.relay_channels(),
+        }
+    }
+}
+
+impl Module for RelayFeed {
+    fn name(&self) -> &'static str {
+        "Relay"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        _cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            if let Event::MessageCreate(msg) = event {
+                if let Some(alias) = self.channels.get(&msg.channel_id.0) {
+                    if !msg.author.bot && msg.content.len() > 0 {
+                        self.relay.push(
+                            alias.clone(),
+                            msg.author.name.clone(),
+                            msg.author.id.0,
+                            msg.content.clone(),
+                            msg.timestamp.clone(),
+                        );
+                    }
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.channels = config.relay_channels();
+        Ok(())
+    }
+}
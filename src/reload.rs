@@ -0,0 +1,96 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::{select, Either};
+use log::{info, warn};
+
+use actix_rt::signal::unix::{signal, SignalKind};
+
+use crate::supervisor::{CancelToken, Supervisor};
+use crate::utils::{
+    config::{Config, SharedConfig},
+    validate,
+};
+
+// How often the config file's modification time is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// Nothing is applied unless the whole file parses and validates
+fn reload(path: &str, shared: &SharedConfig, bus: &UnboundedSender<Arc<Config>>) -> Result<()> {
+    let mut config = Config::load(path)?;
+
+    let problems = validate::check(&config);
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        bail!("{}", problems.join("; "));
+    }
+
+    let running = shared.get();
+    let skipped = config.keep_startup_settings(&running);
+
+    if !skipped.is_empty() {
+        warn!(
+            "Config reload: {} only apply after a restart",
+            skipped.join(", ")
+        );
+    }
+
+    config.logging.apply();
+
+    let config = Arc::new(config);
+    shared.set(config.clone());
+    bus.unbounded_send(config)?;
+
+    Ok(())
+}
+
+async fn task(
+    path: &str,
+    shared: &SharedConfig,
+    bus: &UnboundedSender<Arc<Config>>,
+    token: &CancelToken,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut last_modified = modified(path);
+
+    loop {
+        let trigger = match select(Box::pin(hangup.recv()), Box::pin(token.delay_for(POLL_INTERVAL))).await {
+            Either::Left(_) => "SIGHUP",
+            Either::Right((false, _)) => return Ok(()),
+            Either::Right((true, _)) => {
+                let current = modified(path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                "file change"
+            }
+        };
+
+        info!("Config reload: {} on {}", trigger, path);
+
+        reload(path, shared, bus)
+            .unwrap_or_else(|err| warn!("Config reload failed, keeping the old config: {:#}", err));
+    }
+}
+
+/// Reloads the config on SIGHUP or when the file changes
+pub fn spawn(
+    supervisor: &mut Supervisor,
+    path: String,
+    shared: SharedConfig,
+    bus: UnboundedSender<Arc<Config>>,
+) {
+    supervisor.spawn("Config reload", move |token| {
+        let (path, shared, bus) = (path.clone(), shared.clone(), bus.clone());
+
+        async move { task(&path, &shared, &bus, &token).await }
+    });
+}
@@ -0,0 +1,323 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{Context, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::info;
+use sqlx::any::AnyPool;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_embed_builder::{
+    EmbedAuthorBuilder, EmbedBuilder, EmbedFieldBuilder, EmbedFooterBuilder, ImageSource,
+};
+use twilight_gateway::Event;
+use twilight_http::Client as TwilightHttp;
+use twilight_model::{
+    channel::ReactionType,
+    id::{ChannelId, EmojiId, GuildId, MessageId},
+};
+
+use crate::db::get_u64;
+use crate::events::Module;
+use crate::guild_settings::SettingsStore;
+use crate::messages::get_avatar_url;
+use crate::utils::config::{Config, Starboard};
+
+// Starboard posts kept in memory, older ones are looked up again
+const STARRED_CACHE: usize = 1024;
+// Board messages searched for an earlier post when there's no database
+const BOARD_SEARCH: u64 = 100;
+
+fn emoji_matches(emoji: &ReactionType, starboard: &Starboard) -> bool {
+    match emoji {
+        ReactionType::Unicode { name } => *name == starboard.emoji,
+        ReactionType::Custom { id, name, .. } => custom_emoji_matches(*id, name.as_deref(), starboard),
+    }
+}
+
+fn custom_emoji_matches(id: EmojiId, name: Option<&str>, starboard: &Starboard) -> bool {
+    starboard.emoji == format!("{}:{}", name.unwrap_or_default(), id)
+}
+
+// The guild's starboard with its threshold override applied
+async fn starboard(
+    config: &Config,
+    settings: &SettingsStore,
+    guild_id: Option<GuildId>,
+) -> Option<(GuildId, Starboard)> {
+    let guild_id = guild_id?;
+    let mut starboard = config.starboard_guilds.get(&guild_id.0)?.clone();
+
+    if let Some(threshold) = settings.get(guild_id).await.starboard_threshold {
+        starboard.threshold = threshold;
+    }
+
+    Some((guild_id, starboard))
+}
+
+/// Starred message id to its starboard post
+struct Starred {
+    pool: Option<AnyPool>,
+    posts: HashMap<MessageId, MessageId>,
+    // Insertion order, the oldest is dropped first
+    order: VecDeque<MessageId>,
+}
+
+impl Starred {
+    fn new(pool: Option<AnyPool>) -> Self {
+        Self {
+            pool,
+            posts: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn remember(&mut self, message_id: MessageId, post_id: MessageId) {
+        if self.posts.insert(message_id, post_id).is_none() {
+            self.order.push_back(message_id);
+        }
+
+        while self.order.len() > STARRED_CACHE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.posts.remove(&oldest);
+            }
+        }
+    }
+
+    // Memory first, then the database, without one the board's latest posts if search_board
+    async fn get(
+        &mut self,
+        http: &TwilightHttp,
+        board: ChannelId,
+        message_id: MessageId,
+        search_board: bool,
+    ) -> Result<Option<MessageId>> {
+        if let Some(post) = self.posts.get(&message_id) {
+            return Ok(Some(*post));
+        }
+
+        let post = match &self.pool {
+            Some(pool) => {
+                let row = sqlx::query("SELECT post_id FROM `kleinerbot_starboard` WHERE message_id = ?")
+                    .bind(message_id.0 as i64)
+                    .fetch_optional(pool)
+                    .await?;
+
+                match row {
+                    Some(row) => Some(MessageId(get_u64(&row, "post_id")?)),
+                    None => None,
+                }
+            }
+            None if !search_board => None,
+            None => {
+                let footer = format!("M:{}", message_id);
+                let posts = http.channel_messages(board).limit(BOARD_SEARCH)?.await?;
+
+                posts
+                    .iter()
+                    .find(|post| {
+                        post.embeds.iter().any(|embed| {
+                            embed
+                                .footer
+                                .as_ref()
+                                .map_or(false, |embed_footer| embed_footer.text == footer)
+                        })
+                    })
+                    .map(|post| post.id)
+            }
+        };
+
+        if let Some(post) = post {
+            self.remember(message_id, post);
+        }
+
+        Ok(post)
+    }
+
+    async fn insert(
+        &mut self,
+        guild_id: GuildId,
+        message_id: MessageId,
+        post_id: MessageId,
+    ) -> Result<()> {
+        self.remember(message_id, post_id);
+
+        if let Some(pool) = &self.pool {
+            sqlx::query("INSERT INTO `kleinerbot_starboard` (message_id,guild_id,post_id) VALUES (?, ?, ?)")
+                .bind(message_id.0 as i64)
+                .bind(guild_id.0 as i64)
+                .bind(post_id.0 as i64)
+                .execute(pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Reposts a message once it has enough stars, then keeps its count current
+async fn star(
+    http: &TwilightHttp,
+    starboard: &Starboard,
+    starred: &mut Starred,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> Result<()> {
+    let board = ChannelId(starboard.channel);
+
+    if channel_id == board {
+        return Ok(());
+    }
+
+    let message = http
+        .message(channel_id, message_id)
+        .await?
+        .context("Starboard: Message not found")?;
+
+    let count = message
+        .reactions
+        .iter()
+        .find(|reaction| emoji_matches(&reaction.emoji, starboard))
+        .map_or(0, |reaction| reaction.count);
+
+    let content = format!("{} **{}** <#{}>", starboard.emoji, count, channel_id);
+
+    // Most stars never reach the threshold, only posts that are known without a board search get updated for them
+    let above_threshold = count >= starboard.threshold;
+
+    if let Some(post) = starred.get(http, board, message_id, above_threshold).await? {
+        http.update_message(board, post)
+            .content(content)?
+            .await?;
+        return Ok(());
+    }
+
+    if !above_threshold {
+        return Ok(());
+    }
+
+    let mut embed = EmbedBuilder::new()
+        .color(0xffac33)?
+        .author(match &message.author.avatar {
+            Some(avatar) => EmbedAuthorBuilder::new()
+                .name(&message.author.name)?
+                .icon_url(get_avatar_url(message.author.id, avatar)?),
+            None => EmbedAuthorBuilder::new().name(&message.author.name)?,
+        })
+        .timestamp(&message.timestamp)
+        .field(EmbedFieldBuilder::new(
+            "Source",
+            format!(
+                "[Jump](https://discord.com/channels/{}/{}/{})",
+                guild_id, channel_id, message_id
+            ),
+        )?)
+        .footer(EmbedFooterBuilder::new(format!("M:{}", message_id))?);
+
+    if message.content.len() > 0 {
+        embed = embed.description(&message.content)?;
+    }
+
+    // The first image is shown inline, everything else is linked
+    let image = message
+        .attachments
+        .iter()
+        .position(|attachment| attachment.width.is_some());
+
+    if let Some(index) = image {
+        embed = embed.image(ImageSource::url(&message.attachments[index].url)?);
+    }
+
+    let files: Vec<String> = message
+        .attachments
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| Some(*index) != image)
+        .map(|(_, attachment)| format!("[{}]({})", attachment.filename, attachment.url))
+        .collect();
+
+    if !files.is_empty() {
+        embed = embed.field(EmbedFieldBuilder::new("Attachments", files.join("\n"))?);
+    }
+
+    let post = http
+        .create_message(board)
+        .content(content)?
+        .embed(embed.build()?)?
+        .await?;
+
+    starred.insert(guild_id, message_id, post.id).await?;
+
+    info!("Starboard: Message {} starred", message_id);
+
+    Ok(())
+}
+
+
+/// Reposts starred messages of the guilds in starboard_guilds
+pub struct Starboards {
+    http: TwilightHttp,
+    config: Config,
+    settings: SettingsStore,
+    starred: Starred,
+}
+
+impl Starboards {
+    pub fn new(config: Config, pool: Option<AnyPool>, settings: SettingsStore) -> Self {
+        Self {
+            http: TwilightHttp::new(&config.discord.token),
+            config,
+            settings,
+            starred: Starred::new(pool),
+        }
+    }
+
+    async fn event(&mut self, event: &Event) -> Result<()> {
+        let (guild_id, channel_id, message_id) = match event {
+            Event::ReactionAdd(reaction) => (reaction.guild_id, reaction.channel_id, reaction.message_id),
+            Event::ReactionRemove(reaction) => (reaction.guild_id, reaction.channel_id, reaction.message_id),
+            Event::ReactionRemoveAll(reaction) => (reaction.guild_id, reaction.channel_id, reaction.message_id),
+            Event::ReactionRemoveEmoji(reaction) => (Some(reaction.guild_id), reaction.channel_id, reaction.message_id),
+            _ => return Ok(()),
+        };
+
+        if let Some((guild_id, starboard)) = starboard(&self.config, &self.settings, guild_id).await {
+            // Removing all reactions can drop stars too
+            let stars = match event {
+                Event::ReactionAdd(reaction) => emoji_matches(&reaction.emoji, &starboard),
+                Event::ReactionRemove(reaction) => emoji_matches(&reaction.emoji, &starboard),
+                Event::ReactionRemoveEmoji(reaction) => match reaction.emoji.id {
+                    Some(id) => custom_emoji_matches(id, reaction.emoji.name.as_deref(), &starboard),
+                    None => reaction.emoji.name.as_deref() == Some(starboard.emoji.as_str()),
+                },
+                _ => true,
+            };
+
+            if stars {
+                star(&self.http, &starboard, &mut self.starred, guild_id, channel_id, message_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Module for Starboards {
+    fn name(&self) -> &'static str {
+        "Starboard"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        _cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        self.event(event).boxed_local()
+    }
+
+    // Remembered posts survive the reload
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.config = config.clone();
+        Ok(())
+    }
+}
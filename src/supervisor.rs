@@ -0,0 +1,185 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use futures::channel::oneshot;
+use futures::future::{select, Either, FutureExt, Shared};
+use log::{error, info, warn};
+
+use actix_rt::signal::{
+    ctrl_c,
+    unix::{signal, SignalKind},
+};
+use actix_rt::time::{delay_for, timeout};
+use actix_rt::Arbiter;
+
+use crate::utils::metrics::{Metrics, TaskState};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Resolves once shutdown starts, clones share the same state
+#[derive(Clone)]
+pub struct CancelToken(Shared<oneshot::Receiver<()>>);
+
+impl CancelToken {
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Unpin {
+        self.0.clone().map(|_| ())
+    }
+
+    /// Sleeps for the duration, returns false when shutdown started first
+    pub async fn delay_for(&self, duration: Duration) -> bool {
+        match select(Box::pin(delay_for(duration)), self.cancelled()).await {
+            Either::Left(_) => true,
+            Either::Right(_) => false,
+        }
+    }
+}
+
+/// Owns the long running tasks, restarts them when they fail and stops them on shutdown
+pub struct Supervisor {
+    cancel: oneshot::Sender<()>,
+    token: CancelToken,
+    // Resolves when the task with that name has stopped
+    tasks: Vec<(&'static str, oneshot::Receiver<()>)>,
+    metrics: Metrics,
+}
+
+impl Supervisor {
+    pub fn new(metrics: Metrics) -> Self {
+        let (cancel, receiver) = oneshot::channel();
+
+        Self {
+            cancel,
+            token: CancelToken(receiver.shared()),
+            tasks: Vec::new(),
+            metrics,
+        }
+    }
+
+    pub fn token(&self) -> CancelToken {
+        self.token.clone()
+    }
+
+    /// Runs a task built by `factory` until shutdown, restarting it with backoff whenever it fails or returns
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, mut factory: F)
+    where
+        F: FnMut(CancelToken) -> Fut + 'static,
+        Fut: Future<Output = Result<()>> + 'static,
+    {
+        let token = self.token();
+        let metrics = self.metrics.clone();
+
+        self.track(name, async move {
+            let mut backoff = MIN_BACKOFF;
+
+            loop {
+                metrics.task_state(name, TaskState::Running);
+                let started = Instant::now();
+                // A panic is a failure like any other, the task gets restarted
+                let run = AssertUnwindSafe(factory(token.clone())).catch_unwind();
+                let mut run = Box::pin(run.map(|result| {
+                    result.unwrap_or_else(|panic| Err(anyhow!("panicked: {}", panic_message(&*panic))))
+                }));
+
+                let result = match select(&mut run, token.cancelled()).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => {
+                        // Let it finish what it's doing, tasks watching the token stop on their own
+                        run.await.unwrap_or_else(|err| warn!("{} failed while stopping: {}", name, err));
+                        break;
+                    }
+                };
+
+                match result {
+                    Ok(()) => warn!("{} stopped unexpectedly", name),
+                    Err(err) => warn!("{} failed: {}", name, err),
+                }
+
+                // A task that ran for a while was healthy, start over with a short delay
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = MIN_BACKOFF;
+                }
+
+                metrics.task_state(name, TaskState::Restarting);
+                info!("{} restarting in {}s", name, backoff.as_secs());
+                if !token.delay_for(backoff).await {
+                    break;
+                }
+
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+    }
+
+    /// Waits on a task that handles its own errors and stops when the token is cancelled
+    pub fn track(&mut self, name: &'static str, task: impl Future<Output = ()> + 'static) {
+        let (done, stopped) = oneshot::channel();
+        let metrics = self.metrics.clone();
+
+        metrics.task_state(name, TaskState::Running);
+
+        Arbiter::spawn(async move {
+            // Without this a panic would drop `done` and the task would look like it stopped cleanly
+            match AssertUnwindSafe(task).catch_unwind().await {
+                Ok(()) => metrics.task_state(name, TaskState::Stopped),
+                Err(panic) => {
+                    error!("{} panicked: {}", name, panic_message(&*panic));
+                    metrics.task_state(name, TaskState::Failed);
+                }
+            }
+            let _ = done.send(());
+        });
+
+        self.tasks.push((name, stopped));
+    }
+
+    /// Cancels every task and waits up to `grace` for them, returns whether all of them stopped in time
+    pub async fn shutdown(self, grace: Duration) -> bool {
+        let _ = self.cancel.send(());
+
+        // One shared deadline, so waiting on them in order costs no extra time
+        let deadline = Instant::now() + grace;
+        let mut running = Vec::new();
+
+        for (name, stopped) in self.tasks {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if timeout(left, stopped).await.is_err() {
+                running.push(name);
+            }
+        }
+
+        if running.is_empty() {
+            info!("All tasks stopped");
+            true
+        } else {
+            warn!("Tasks still running after {}s: {}", grace.as_secs(), running.join(", "));
+            false
+        }
+    }
+}
+
+/// Waits for SIGINT or SIGTERM, returns which one arrived
+pub async fn signal_received() -> Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    let name = match select(Box::pin(ctrl_c()), Box::pin(terminate.recv())).await {
+        Either::Left((result, _)) => {
+            result?;
+            "SIGINT"
+        }
+        Either::Right(_) => "SIGTERM",
+    };
+
+    Ok(name)
+}
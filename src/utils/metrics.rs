@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Stopped,
+    // Panicked outside of a restart loop
+    Failed,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ModuleHealth {
+    pub handled: u64,
+    pub failed: u64,
+    pub last_success: Option<Instant>,
+}
+
+#[derive(Default)]
+struct MetricsData {
+    events: BTreeMap<String, u64>,
+    log_messages: u64,
+    cache_misses: BTreeMap<&'static str, u64>,
+    attachment_cache_bytes: u64,
+    mysql_polls: BTreeMap<&'static str, u64>,
+    api_requests: BTreeMap<u16, u64>,
+    modules: BTreeMap<&'static str, ModuleHealth>,
+    tasks: BTreeMap<&'static str, TaskState>,
+}
+
+/// Counters for /metrics and /healthz, clones share the same values
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<MetricsData>>);
+
+impl Metrics {
+    pub fn event(&self, kind: String) {
+        *self.0.lock().unwrap().events.entry(kind).or_default() += 1;
+    }
+
+    pub fn log_message(&self) {
+        self.0.lock().unwrap().log_messages += 1;
+    }
+
+    pub fn cache_miss(&self, what: &'static str) {
+        *self.0.lock().unwrap().cache_misses.entry(what).or_default() += 1;
+    }
+
+    pub fn set_attachment_cache_bytes(&self, bytes: u64) {
+        self.0.lock().unwrap().attachment_cache_bytes = bytes;
+    }
+
+    pub fn mysql_poll(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        *self.0.lock().unwrap().mysql_polls.entry(result).or_default() += 1;
+    }
+
+    pub fn api_request(&self, status: u16) {
+        *self.0.lock().unwrap().api_requests.entry(status).or_default() += 1;
+    }
+
+    pub fn module_result(&self, module: &'static str, result: &Result<()>) {
+        let mut data = self.0.lock().unwrap();
+        let health = data.modules.entry(module).or_default();
+
+        health.handled += 1;
+        // Errors can quote user input, they only go to the log
+        match result {
+            Ok(()) => health.last_success = Some(Instant::now()),
+            Err(_) => health.failed += 1,
+        }
+    }
+
+    pub fn task_state(&self, task: &'static str, state: TaskState) {
+        self.0.lock().unwrap().tasks.insert(task, state);
+    }
+
+    pub fn tasks(&self) -> BTreeMap<&'static str, TaskState> {
+        self.0.lock().unwrap().tasks.clone()
+    }
+
+    pub fn modules(&self) -> BTreeMap<&'static str, ModuleHealth> {
+        self.0.lock().unwrap().modules.clone()
+    }
+
+    /// Prometheus text format, latencies are the shard heartbeat averages
+    pub fn render(&self, latencies: &[(u64, Option<Duration>)]) -> String {
+        let data = self.0.lock().unwrap();
+        let mut out = String::new();
+
+        // Writing to a String can't fail
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        metric(
+            "kleinerbot_gateway_latency_seconds",
+            "gauge",
+            "Average heartbeat latency per shard",
+            latencies
+                .iter()
+                .filter_map(|(shard, latency)| {
+                    latency.map(|latency| (label("shard", shard), latency.as_secs_f64().to_string()))
+                })
+                .collect(),
+        );
+        metric(
+            "kleinerbot_gateway_events_total",
+            "counter",
+            "Gateway events dispatched to modules, by type",
+            data.events
+                .iter()
+                .map(|(kind, count)| (label("type", kind), count.to_string()))
+                .collect(),
+        );
+        metric(
+            "kleinerbot_module_failures_total",
+            "counter",
+            "Events a module failed to handle",
+            data.modules
+                .iter()
+                .map(|(module, health)| (label("module", module), health.failed.to_string()))
+                .collect(),
+        );
+        metric(
+            "kleinerbot_log_messages_total",
+            "counter",
+            "Messages posted to log channels",
+            vec![(String::new(), data.log_messages.to_string())],
+        );
+        metric(
+            "kleinerbot_cache_misses_total",
+            "counter",
+            "Lookups the gateway cache couldn't answer",
+            data.cache_misses
+                .iter()
+                .map(|(what, count)| (label("what", what), count.to_string()))
+                .collect(),
+        );
+        metric(
+            "kleinerbot_attachment_cache_bytes",
+            "gauge",
+            "Attachments kept to restore deleted messages",
+            vec![(String::new(), data.attachment_cache_bytes.to_string())],
+        );
+        metric(
+            "kleinerbot_mysql_polls_total",
+            "counter",
+            "Game server list polls, by result",
+            data.mysql_polls
+                .iter()
+                .map(|(result, count)| (label("result", result), count.to_string()))
+                .collect(),
+        );
+        metric(
+            "kleinerbot_api_requests_total",
+            "counter",
+            "Web API requests, by response status",
+            data.api_requests
+                .iter()
+                .map(|(status, count)| (label("status", status), count.to_string()))
+                .collect(),
+        );
+        metric(
+            "kleinerbot_task_up",
+            "gauge",
+            "Whether a supervised task is running",
+            data.tasks
+                .iter()
+                .map(|(task, state)| {
+                    let up = if *state == TaskState::Running { 1 } else { 0 };
+                    (label("task", task), up.to_string())
+                })
+                .collect(),
+        );
+
+        out
+    }
+}
+
+fn label(name: &str, value: impl ToString) -> String {
+    let value = value
+        .to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+
+    format!("{{{}=\"{}\"}}", name, value)
+}
+
+pub trait CacheMiss<T> {
+    /// Counts the miss before turning it into a "<what> cache miss" error
+    fn or_miss(self, metrics: &Metrics, what: &'static str) -> Result<T>;
+}
+
+impl<T> CacheMiss<T> for Option<T> {
+    fn or_miss(self, metrics: &Metrics, what: &'static str) -> Result<T> {
+        self.ok_or_else(|| {
+            metrics.cache_miss(what);
+            anyhow!("{} cache miss", what)
+        })
+    }
+}
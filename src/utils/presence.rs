@@ -0,0 +1,132 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Deserialize;
+use twilight_gateway::Cluster;
+use twilight_model::gateway::{
+    payload::UpdateStatus,
+    presence::{Activity, ActivityType, Status},
+};
+
+pub const ACTIVITY_TEMPLATE: Activity = Activity {
+    application_id: None,
+    assets: None,
+    created_at: None,
+    details: None,
+    flags: None,
+    id: None,
+    instance: None,
+    kind: ActivityType::Playing,
+    name: String::new(),
+    emoji: None,
+    party: None,
+    secrets: None,
+    state: None,
+    timestamps: None,
+    url: None,
+};
+
+// Twilight (de)serializes activity kinds as integers, web clients send names
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Playing,
+    Streaming,
+    Listening,
+    Watching,
+}
+
+impl Default for Kind {
+    fn default() -> Self {
+        Kind::Playing
+    }
+}
+
+impl From<Kind> for ActivityType {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Playing => ActivityType::Playing,
+            Kind::Streaming => ActivityType::Streaming,
+            Kind::Listening => ActivityType::Listening,
+            Kind::Watching => ActivityType::Watching,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OnlineStatus {
+    Online,
+    Idle,
+    Dnd,
+    Invisible,
+}
+
+impl Default for OnlineStatus {
+    fn default() -> Self {
+        OnlineStatus::Online
+    }
+}
+
+impl From<OnlineStatus> for Status {
+    fn from(status: OnlineStatus) -> Self {
+        match status {
+            OnlineStatus::Online => Status::Online,
+            OnlineStatus::Idle => Status::Idle,
+            OnlineStatus::Dnd => Status::DoNotDisturb,
+            OnlineStatus::Invisible => Status::Invisible,
+        }
+    }
+}
+
+pub fn activity(kind: Kind, name: impl Into<String>) -> Activity {
+    let mut activity = ACTIVITY_TEMPLATE.clone();
+    activity.kind = kind.into();
+    activity.name = name.into();
+    activity
+}
+
+#[derive(Clone, Copy)]
+enum Hold {
+    Until(Instant),
+    // Until the next API call replaces it
+    Forever,
+}
+
+/// Presence lock shared between the web API and the MySQL rotator
+#[derive(Clone, Default)]
+pub struct PresenceOverride {
+    hold: Arc<Mutex<Option<Hold>>>,
+}
+
+impl PresenceOverride {
+    pub fn set(&self, duration: Duration) {
+        *self.hold.lock().unwrap() = Some(Hold::Until(Instant::now() + duration));
+    }
+
+    pub fn set_forever(&self) {
+        *self.hold.lock().unwrap() = Some(Hold::Forever);
+    }
+
+    pub fn is_active(&self) -> bool {
+        let mut hold = self.hold.lock().unwrap();
+        match *hold {
+            Some(Hold::Forever) => true,
+            Some(Hold::Until(deadline)) if deadline > Instant::now() => true,
+            Some(Hold::Until(_)) => {
+                *hold = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+// Presence is per connection, so every shard has to be told
+pub async fn update(cluster: &Cluster, status: &UpdateStatus) -> Result<()> {
+    for shard in cluster.shards() {
+        shard.command(status).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+const RELAY_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RelayMessage {
+    pub cursor: u64,
+    pub channel: String,
+    pub author: String,
+    pub author_id: u64,
+    pub content: String,
+    pub timestamp: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RelayBatch {
+    pub messages: Vec<RelayMessage>,
+    /// Cursor to send with the next poll
+    pub cursor: u64,
+    /// Messages that fell out of the buffer before this poll
+    pub missed: u64,
+}
+
+#[derive(Default)]
+struct RelayData {
+    messages: VecDeque<RelayMessage>,
+    next: u64,
+}
+
+/// Discord messages queued for game servers, read through the web API
+#[derive(Clone, Default)]
+pub struct Relay(Arc<Mutex<RelayData>>);
+
+impl Relay {
+    pub fn push(
+        &self,
+        channel: String,
+        author: String,
+        author_id: u64,
+        content: String,
+        timestamp: String,
+    ) {
+        let mut data = self.0.lock().unwrap();
+
+        if data.messages.len() >= RELAY_CAPACITY {
+            data.messages.pop_front();
+        }
+
+        let cursor = data.next;
+        data.next += 1;
+
+        data.messages.push_back(RelayMessage {
+            cursor,
+            channel,
+            author,
+            author_id,
+            content,
+            timestamp,
+        });
+    }
+
+    /// Messages from `cursor` on, a client without a cursor starts from now, one past the counter starts over
+    pub fn since(&self, cursor: Option<u64>, filter: impl Fn(&RelayMessage) -> bool) -> RelayBatch {
+        let data = self.0.lock().unwrap();
+
+        let oldest = data
+            .messages
+            .front()
+            .map_or(data.next, |message| message.cursor);
+
+        // A cursor from before a restart is ahead of the counter, it gets everything still buffered
+        let cursor = match cursor {
+            Some(cursor) if cursor > data.next => oldest,
+            Some(cursor) => cursor,
+            None => data.next,
+        };
+
+        RelayBatch {
+            messages: data
+                .messages
+                .iter()
+                .filter(|message| message.cursor >= cursor && filter(message))
+                .cloned()
+                .collect(),
+            cursor: data.next,
+            missed: oldest.saturating_sub(cursor),
+        }
+    }
+}
@@ -0,0 +1,14 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Discord snowflakes count milliseconds from 2015
+const DISCORD_EPOCH: u64 = 1420070400000;
+
+/// Time since the object with this id was created
+pub fn age(id: u64) -> Duration {
+    let created = (id >> 22) + DISCORD_EPOCH;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64);
+
+    Duration::from_millis(now.saturating_sub(created))
+}
@@ -0,0 +1,278 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::utils::config::{AutomodAction, Config, RaidAction, RuleKind};
+
+/// A config mistake and where in the YAML it is
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn add(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Problem {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    // The web server would fail on a missing or unusable file at startup
+    fn file<T>(&mut self, path: &str, file: &str, load: impl FnOnce() -> anyhow::Result<T>) {
+        if file.is_empty() {
+            self.add(path, "required when web.ssl is on");
+        } else if !Path::new(file).is_file() {
+            self.add(path, format!("{} does not exist", file));
+        } else if let Err(err) = load() {
+            self.add(path, format!("{:#}", err));
+        }
+    }
+}
+
+/// Everything wrong with the config, empty when it's usable
+pub fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = Problems::default();
+
+    for path in &config.unknown_keys {
+        problems.add(path.as_str(), "is not a setting");
+    }
+
+    if config.discord.token.is_empty() {
+        problems.add("discord.token", "must not be empty");
+    }
+
+    if config.discord.shard_count == Some(0) {
+        problems.add("discord.shard_count", "must be at least 1, or unset");
+    }
+
+    if config.logging.level().is_none() {
+        problems.add("logging.level", "must be off, error, warn, info, debug or trace");
+    }
+
+    if config.prefix.is_empty() {
+        problems.add("prefix", "must not be empty");
+    }
+    for (guild, prefix) in &config.guild_prefixes {
+        if prefix.is_empty() {
+            problems.add(format!("guild_prefixes.{}", guild), "must not be empty");
+        }
+    }
+
+    let channels = config.web.as_ref().map(|web| &web.channels);
+
+    for (i, alias) in config.relay_channels.iter().enumerate() {
+        if !channels.map_or(false, |channels| channels.contains_key(alias)) {
+            problems.add(format!("relay_channels[{}]", i), format!("{} is not in web.channels", alias));
+        }
+    }
+
+    if let Some(web) = &config.web {
+        if web.ssl {
+            problems.file("web.cert", &web.cert, || web.load_cert_chain());
+            problems.file("web.privkey", &web.privkey, || web.load_private_key());
+        }
+
+        if let Some(alias) = &web.default_channel {
+            if !web.channels.contains_key(alias) {
+                problems.add("web.default_channel", format!("{} is not in web.channels", alias));
+            }
+        }
+
+        let allowed: HashSet<u64> = web.channels.values().copied().collect();
+        let mut ids = HashSet::new();
+        for (i, key) in web.api_keys.iter().enumerate() {
+            if !ids.insert(&key.id) {
+                problems.add(format!("web.api_keys[{}].id", i), format!("{} is used twice", key.id));
+            }
+            if key.token.is_empty() {
+                problems.add(format!("web.api_keys[{}].token", i), "must not be empty");
+            }
+            for (j, channel) in key.channels.iter().flatten().enumerate() {
+                if !allowed.contains(channel) {
+                    problems.add(format!("web.api_keys[{}].channels[{}]", i, j), format!("{} is not in web.channels", channel));
+                }
+            }
+        }
+    }
+
+    if let Some(mysql) = &config.mysql {
+        if mysql.user.is_empty() {
+            problems.add("mysql.user", "must not be empty");
+        }
+        if mysql.dbname.is_empty() {
+            problems.add("mysql.dbname", "must not be empty");
+        }
+    }
+
+    if let Some(database) = &config.database {
+        if !database.url.starts_with("mysql://") && !database.url.starts_with("sqlite:") {
+            problems.add("database.url", "must start with mysql:// or sqlite:");
+        }
+    }
+
+    let has_database = config.database.is_some();
+
+    for (i, rule) in config.automod_rules.iter().enumerate() {
+        let path = format!("automod_rules[{}]", i);
+
+        match &rule.kind {
+            RuleKind::Words { patterns } => {
+                for (j, pattern) in patterns.iter().enumerate() {
+                    if let Err(err) = Regex::new(pattern) {
+                        problems.add(format!("{}.patterns[{}]", path, j), err.to_string());
+                    }
+                }
+            }
+            RuleKind::Spam { repeats, window } => {
+                if *repeats < 2 {
+                    problems.add(format!("{}.repeats", path), "must be at least 2");
+                }
+                if *window == 0 {
+                    problems.add(format!("{}.window", path), "must be at least 1");
+                }
+            }
+            RuleKind::Caps { ratio, .. } => {
+                if !(0.0..=1.0).contains(ratio) {
+                    problems.add(format!("{}.ratio", path), "must be between 0 and 1");
+                }
+            }
+            _ => {}
+        }
+
+        let punishes = rule.action == AutomodAction::Warn || rule.action == AutomodAction::Mute;
+        if punishes && !has_database {
+            problems.add(format!("{}.action", path), "warn and mute need the database section");
+        }
+    }
+
+    if config.antiraid_enabled {
+        if config.antiraid_joins == 0 {
+            problems.add("antiraid_joins", "must be at least 1");
+        }
+        if config.antiraid_window == 0 {
+            problems.add("antiraid_window", "must be at least 1");
+        }
+        if config.antiraid_actions.is_empty() {
+            problems.add("antiraid_actions", "must list at least one action");
+        }
+        // Raid mode is only ended by the raid command, until then every new member is kicked
+        if config.antiraid_actions.contains(&RaidAction::Kick) && !config.commands_enabled {
+            problems.add("antiraid_actions", "kick needs commands_enabled, raid mode can't be turned off without it");
+        }
+    }
+
+    for (guild, starboard) in &config.starboard_guilds {
+        if starboard.threshold == 0 {
+            problems.add(format!("starboard_guilds.{}.threshold", guild), "must be at least 1");
+        }
+    }
+
+    for (i, hook) in config.webhooks.iter().enumerate() {
+        if !hook.url.starts_with("https://") && !hook.url.starts_with("http://") {
+            problems.add(format!("webhooks[{}].url", i), "must be an http(s) URL");
+        }
+        if hook.secret.is_empty() {
+            problems.add(format!("webhooks[{}].secret", i), "must not be empty");
+        }
+        if hook.events.is_empty() {
+            problems.add(format!("webhooks[{}].events", i), "must list at least one event");
+        }
+    }
+
+    problems.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("discord:\n  token: abc\n{}", yaml)).unwrap()
+    }
+
+    fn paths(config: &Config) -> Vec<String> {
+        check(config).into_iter().map(|problem| problem.path).collect()
+    }
+
+    #[test]
+    fn minimal_config_is_fine() {
+        assert!(paths(&config("")).is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let mut config = config("");
+        config.unknown_keys = vec!["web.prot".to_string()];
+
+        let problems = check(&config);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].to_string(), "web.prot: is not a setting");
+    }
+
+    #[test]
+    fn empty_token_and_prefix() {
+        let config: Config = serde_yaml::from_str("discord:\n  token: ''\nprefix: ''").unwrap();
+        assert_eq!(paths(&config), ["discord.token", "prefix"]);
+    }
+
+    #[test]
+    fn relay_channels_need_an_alias() {
+        let config = config("web:\n  ssl: false\n  channels:\n    general: 1\nrelay_channels: [general, typo]");
+        assert_eq!(paths(&config), ["relay_channels[1]"]);
+    }
+
+    #[test]
+    fn automod_rules_are_checked() {
+        let config = config(
+            "automod_rules:\n  - kind: words\n    patterns: ['(']\n    action: delete\n  - kind: invites\n    action: warn",
+        );
+        assert_eq!(
+            paths(&config),
+            ["automod_rules[0].patterns[0]", "automod_rules[1].action"]
+        );
+    }
+
+    #[test]
+    fn raid_kick_needs_commands() {
+        let without = config("commands_enabled: false\nantiraid_enabled: true\nantiraid_actions: [alert, kick]");
+        assert_eq!(paths(&without), ["antiraid_actions"]);
+
+        let with = config("antiraid_enabled: true\nantiraid_actions: [alert, kick]");
+        assert!(paths(&with).is_empty());
+    }
+
+    #[test]
+    fn ssl_files_are_read() {
+        let missing = config("web:\n  cert: /nonexistent/cert.pem\n  privkey: ''");
+        assert_eq!(paths(&missing), ["web.cert", "web.privkey"]);
+
+        let dir = std::env::temp_dir().join(format!("kleinerbot-validate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+
+        let empty = empty.to_str().unwrap();
+        let unusable = config(&format!("web:\n  cert: {}\n  privkey: {}", empty, empty));
+        let problems = check(&unusable);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].path, "web.cert");
+        assert!(problems[0].message.contains("has no certificate"));
+        assert_eq!(problems[1].path, "web.privkey");
+        assert!(problems[1].message.contains("has no RSA private key"));
+    }
+}
@@ -1,19 +1,36 @@
-use actix_web::{http::StatusCode, web, App, HttpServer, Responder};
-use anyhow::{Context, Result};
-use log::{error, info, warn};
-use std::{fs::File, io::BufReader, error::Error};
-use rust_tls::internal::pemfile::{certs, rsa_private_keys};
+mod api;
+mod error;
+
+use actix_web::{dev::Service, http::{header, StatusCode}, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use anyhow::{anyhow, Result};
+use futures::future::{select, Either};
+use log::{info, warn};
+use std::error::Error;
 use rust_tls::{NoClientAuth, ServerConfig};
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use twilight_gateway::{shard::Stage, Cluster};
+use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder, EmbedFooterBuilder, ImageSource};
 use twilight_http::Client as TwilightHttp;
-use twilight_model::id::ChannelId;
-
-use actix_rt::Arbiter;
-
-use crate::utils::config::Config;
-
-const CID_GITHUB: ChannelId = ChannelId(478623542380855306);
+use twilight_model::{
+    channel::{embed::Embed, Message},
+    gateway::{payload::UpdateStatus, presence::Status},
+    id::ChannelId,
+};
+
+use crate::mysql::ServerList;
+use crate::supervisor::{CancelToken, Supervisor};
+use crate::utils::{
+    config::{ApiAction, ApiKey, Config},
+    metrics::{Metrics, TaskState},
+    presence::{self, Kind, OnlineStatus, PresenceOverride},
+    relay::Relay,
+};
+
+use self::error::ApiError;
 
 #[derive(Deserialize)]
 struct AuthorOrRepo {
@@ -36,149 +53,507 @@ struct Github {
 
 struct BotData {
     http: TwilightHttp,
-    token: String,
+    cluster: Cluster,
+    presence: PresenceOverride,
+    servers: Option<ServerList>,
+    relay: Relay,
+    metrics: Metrics,
+    keys: Vec<ApiKey>,
+    // Alias -> id, the values double as the channel allowlist
+    channels: HashMap<String, u64>,
+    default_channel: Option<u64>,
+    // Where GitHub pushes are announced
+    github_channel: Option<ChannelId>,
+    // Key id -> start of the current minute window and requests made in it
+    usage: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl BotData {
+    // Bearer header for API clients, query parameter for older scripts
+    fn authorize(&self, req: &HttpRequest, query_token: Option<&str>) -> Result<&ApiKey, ApiError> {
+        let header_token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        let token = header_token.or(query_token).ok_or_else(ApiError::unauthorized)?;
+
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.token == token)
+            .ok_or_else(ApiError::forbidden)?;
+
+        if key.rate_limit > 0 {
+            let mut usage = self.usage.lock().unwrap();
+            let now = Instant::now();
+            let (start, count) = usage.entry(key.id.clone()).or_insert((now, 0));
+
+            if now.duration_since(*start) >= Duration::from_secs(60) {
+                *start = now;
+                *count = 0;
+            }
+
+            if *count >= key.rate_limit {
+                warn!("API key {} is rate limited", key.id);
+                return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"));
+            }
+
+            *count += 1;
+        }
+
+        Ok(key)
+    }
+
+    fn resolve_channel(&self, channel: Option<&ChannelRef>) -> Result<ChannelId, ApiError> {
+        let id = match channel {
+            Some(ChannelRef::Id(id)) => *id,
+            Some(ChannelRef::Alias(alias)) => match self.channels.get(alias) {
+                Some(id) => *id,
+                None => alias
+                    .parse()
+                    .map_err(|_| ApiError::bad_request(format!("Unknown channel {}", alias)))?,
+            },
+            None => self
+                .default_channel
+                .ok_or_else(|| ApiError::bad_request("Missing channel"))?,
+        };
+
+        if !self.channels.values().any(|allowed| *allowed == id) {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "Channel is not allowed"));
+        }
+
+        Ok(ChannelId(id))
+    }
+}
+
+fn require(key: &ApiKey, action: ApiAction) -> Result<(), ApiError> {
+    if key.allows(action) {
+        Ok(())
+    } else {
+        Err(ApiError::new(StatusCode::FORBIDDEN, format!("API key is not allowed to {:?}", action)))
+    }
 }
 
 #[derive(Deserialize, Debug)]
 struct Info {
-    token: String,
+    token: Option<String>,
     #[serde(default)]
     github: bool,
 }
 
+#[derive(Deserialize, Debug)]
+struct PresenceBody {
+    activity: String,
+    #[serde(default)]
+    activity_kind: Kind,
+    #[serde(default)]
+    status: OnlineStatus,
+    // Seconds to hold the activity before the MySQL rotator may replace it, 0 holds it until the next call
+    #[serde(default)]
+    activity_expiry: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageBody {
+    #[serde(default)]
+    content: String,
+    #[serde(default, alias = "channelID")]
+    channel: Option<ChannelRef>,
+    // Only reachable through JSON bodies, urlencoded forms can't nest
+    #[serde(default)]
+    embed: Option<BodyEmbed>,
+}
+
+/// Alias from `web.channels` or a raw id from that list
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ChannelRef {
+    Id(u64),
+    Alias(String),
+}
+
+/// Combined body of the legacy catch-all endpoint
 #[derive(Deserialize, Debug)]
 struct Body {
     #[serde(default)]
     activity: String,
     #[serde(default)]
+    activity_kind: Kind,
+    #[serde(default)]
+    status: OnlineStatus,
+    #[serde(default)]
+    activity_expiry: u64,
+    #[serde(default)]
     content: String,
-    #[serde(default = "Body::default_channel")]
-    channelID: u64,
+    #[serde(default, alias = "channelID")]
+    channel: Option<ChannelRef>,
+    #[serde(default)]
+    embed: Option<BodyEmbed>,
 }
 
 impl Body {
-    fn default_channel() -> u64 {
-        544557150064738315
+    fn split(self) -> (Option<PresenceBody>, Option<MessageBody>) {
+        let presence = if self.activity.len() > 0 {
+            Some(PresenceBody {
+                activity: self.activity,
+                activity_kind: self.activity_kind,
+                status: self.status,
+                activity_expiry: self.activity_expiry,
+            })
+        } else {
+            None
+        };
+
+        let message = if self.content.len() > 0 || self.embed.is_some() || presence.is_none() {
+            Some(MessageBody {
+                content: self.content,
+                channel: self.channel,
+                embed: self.embed,
+            })
+        } else {
+            None
+        };
+
+        (presence, message)
     }
 }
 
-async fn request(
-    info: web::Query<Info>,
-    body: bytes::Bytes,
-    http: web::Data<Mutex<BotData>>,
-) -> impl Responder {
-    let result: Result<_> = try {
-        let data = http.lock().unwrap(); // Static data
+fn parse_body<T: DeserializeOwned>(req: &HttpRequest, body: &[u8]) -> Result<T, ApiError> {
+    let json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/json"));
+
+    let parsed = if json {
+        serde_json::from_slice(body).map_err(|err| err.to_string())
+    } else {
+        serde_urlencoded::from_bytes(body).map_err(|err| err.to_string())
+    };
+
+    parsed.map_err(|err| ApiError::bad_request(format!("Body parse failure: {}", err)))
+}
+
+#[derive(Deserialize, Debug)]
+struct BodyField {
+    name: String,
+    value: String,
+    #[serde(default)]
+    inline: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct BodyEmbed {
+    title: Option<String>,
+    description: Option<String>,
+    color: Option<u32>,
+    #[serde(default)]
+    fields: Vec<BodyField>,
+    footer: Option<String>,
+    image: Option<String>,
+}
 
-        let token = &info.token;
-        if token.clone() != data.token {
-            return format!("Invalid token {}", token).with_status(StatusCode::FORBIDDEN);
+impl BodyEmbed {
+    fn build(&self) -> Result<Embed> {
+        let mut embed = EmbedBuilder::new();
+
+        if let Some(title) = &self.title {
+            embed = embed.title(title)?;
+        }
+        if let Some(description) = &self.description {
+            embed = embed.description(description)?;
+        }
+        if let Some(color) = self.color {
+            embed = embed.color(color)?;
+        }
+        for field in &self.fields {
+            let mut builder = EmbedFieldBuilder::new(&field.name, &field.value)?;
+            if field.inline {
+                builder = builder.inline();
+            }
+            embed = embed.field(builder);
+        }
+        if let Some(footer) = &self.footer {
+            embed = embed.footer(EmbedFooterBuilder::new(footer)?);
+        }
+        if let Some(image) = &self.image {
+            embed = embed.image(ImageSource::url(image)?);
         }
 
-        let http = &data.http;
+        Ok(embed.build()?)
+    }
+}
 
-        let bodystr = std::str::from_utf8(&body)?;
+async fn send_message(data: &BotData, key: &ApiKey, body: &MessageBody) -> Result<Message, ApiError> {
+    require(key, ApiAction::SendMessage)?;
 
-        if info.github {
-            info!("Handling web hook");
-            let github: Github = serde_json::from_str(&bodystr)?;
+    let channel = data.resolve_channel(body.channel.as_ref())?;
 
-            //It's api so it won't break too
-            let r#ref = github
-                .r#ref
-                .split('/')
-                .last()
-                .context("Failed to get split result")?;
+    if !key.allows_channel(channel.0) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "API key is not allowed to post in this channel"));
+    }
 
-            let commits = github.commits;
+    let content = &body.content;
+    if (content.len() < 1 && body.embed.is_none()) || content.len() > 2000 {
+        return Err(ApiError::bad_request("Content size is invalid"));
+    }
 
-            let regex = regex::Regex::new(r"(?:\r\n|\r|\n)")?;
+    info!("Forwarding message from {}: {}", key.id, content);
 
-            let mut text = String::new();
+    let mut message = data.http.create_message(channel);
 
-            for commit in &commits {
-                text += &format!(
-                    "$Commit #{} by {}\n {}\n\n",
-                    &commit.id[..7],
-                    commit.author.name,
-                    regex.replace(commit.message.as_str(), "\n ")
-                );
-            }
+    if content.len() > 0 {
+        message = message.content(content)?;
+    }
 
-            &http
-                .create_message(CID_GITHUB)
-                .content(format!(
-                    "```md\n{} new commit(s) of {}:{}\n {} ```",
-                    commits.len(),
-                    github.repository.name,
-                    r#ref,
-                    text
-                ))?
-                .await?;
-
-            return format!("").with_status(StatusCode::OK);
-        }
+    if let Some(embed) = &body.embed {
+        let embed = embed
+            .build()
+            .map_err(|err| ApiError::bad_request(format!("Invalid embed: {}", err)))?;
+        message = message.embed(embed)?;
+    }
 
-        match web::Query::<Body>::from_query(&bodystr) {
-            Ok(qsl) => {
-                let content = &qsl.content;
-                if content.len() < 1 || content.len() > 2000 {
-                    return format!("Content size is invalid")
-                        .with_status(StatusCode::INTERNAL_SERVER_ERROR);
-                }
+    Ok(message.await?)
+}
 
-                info!("Forwarding message: {}", content);
+async fn set_presence(data: &BotData, key: &ApiKey, body: &PresenceBody) -> Result<(), ApiError> {
+    require(key, ApiAction::SetPresence)?;
 
-                &http
-                    .create_message(ChannelId(qsl.channelID))
-                    .content(content)?
-                    .await?;
+    if body.activity.len() < 1 || body.activity.len() > 128 {
+        return Err(ApiError::bad_request("Activity size is invalid"));
+    }
 
-                format!("Successfully passed message").with_status(StatusCode::OK)
-            }
-            Err(err) => {
-                error!("QSL Parse: {}", err);
-                return format!("Internal error").with_status(StatusCode::INTERNAL_SERVER_ERROR);
+    info!("Setting activity from {}: {}", key.id, body.activity);
+
+    let activity = presence::activity(body.activity_kind, &body.activity);
+
+    let status = UpdateStatus::new(vec!(activity), false, None, Status::from(body.status));
+    presence::update(&data.cluster, &status).await.map_err(|err| {
+        warn!("Setting activity from {} failed: {:#}", key.id, err);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Updating the presence failed")
+    })?;
+
+    // 0 holds the activity until the next presence call
+    if body.activity_expiry > 0 {
+        data.presence.set(Duration::from_secs(body.activity_expiry));
+    } else {
+        data.presence.set_forever();
+    }
+
+    Ok(())
+}
+
+async fn post_github(data: &BotData, key: &ApiKey, body: &[u8]) -> Result<(), ApiError> {
+    require(key, ApiAction::Github)?;
+
+    let channel = data
+        .github_channel
+        .ok_or_else(|| ApiError::unavailable("web.github_channel is not configured"))?;
+
+    info!("Handling web hook from {}", key.id);
+    let github: Github = serde_json::from_slice(body)
+        .map_err(|err| ApiError::bad_request(format!("Invalid push payload: {}", err)))?;
+
+    //It's api so it won't break too
+    let r#ref = github.r#ref.split('/').last().unwrap_or_default();
+
+    let commits = github.commits;
+
+    let regex = regex::Regex::new(r"(?:\r\n|\r|\n)")?;
+
+    let mut text = String::new();
+
+    for commit in &commits {
+        text += &format!(
+            "$Commit #{} by {}\n {}\n\n",
+            &commit.id[..7.min(commit.id.len())],
+            commit.author.name,
+            regex.replace(commit.message.as_str(), "\n ")
+        );
+    }
+
+    data.http
+        .create_message(channel)
+        .content(format!(
+            "```md\n{} new commit(s) of {}:{}\n {} ```",
+            commits.len(),
+            github.repository.name,
+            r#ref,
+            text
+        ))?
+        .await?;
+
+    Ok(())
+}
+
+// Pre-v1 endpoint kept for existing game server scripts
+async fn request(
+    req: HttpRequest,
+    info: web::Query<Info>,
+    body: bytes::Bytes,
+    data: web::Data<BotData>,
+) -> impl Responder {
+    let result: Result<_, ApiError> = try {
+        let key = data.authorize(&req, info.token.as_deref())?;
+
+        if info.github {
+            post_github(&data, key, &body).await?;
+            return String::new().with_status(StatusCode::OK);
+        }
+
+        let (presence, message) = parse_body::<Body>(&req, &body)?.split();
+
+        if let Some(presence) = presence {
+            set_presence(&data, key, &presence).await?;
+        }
+
+        match message {
+            Some(message) => {
+                send_message(&data, key, &message).await?;
+                format!("Successfully passed message")
             }
+            None => format!("Successfully set activity"),
         }
     };
 
     match result {
-        Ok(a) => a,
-        Err(err) => {
-            error!("Web request error: {}", err);
-            format!("Internal error").with_status(StatusCode::INTERNAL_SERVER_ERROR)
-        }
+        Ok(a) => a.with_status(StatusCode::OK),
+        Err(err) => err.message().to_string().with_status(err.status()),
     }
 }
 
-async fn task(config: &Config) -> Result<(),Box<dyn Error>> {
+async fn prometheus(data: web::Data<BotData>) -> HttpResponse {
+    let mut latencies: Vec<(u64, Option<Duration>)> = data
+        .cluster
+        .info()
+        .into_iter()
+        .map(|(shard, info)| (shard, info.latency().average()))
+        .collect();
+    latencies.sort_by_key(|(shard, _)| *shard);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.metrics.render(&latencies))
+}
+
+// 503 unless every shard is connected and every supervised task is running
+async fn healthz(data: web::Data<BotData>) -> HttpResponse {
+    let shards = data.cluster.info();
+    let connected = shards
+        .values()
+        .filter(|info| info.stage() == Stage::Connected)
+        .count();
+
+    let tasks = data.metrics.tasks();
+    let healthy = !shards.is_empty()
+        && connected == shards.len()
+        && tasks.values().all(|state| *state == TaskState::Running);
+
+    let modules: BTreeMap<&str, _> = data
+        .metrics
+        .modules()
+        .into_iter()
+        .map(|(name, health)| {
+            (name, json!({
+                "handled": health.handled,
+                "failed": health.failed,
+                "last_success_secs": health.last_success.map(|time| time.elapsed().as_secs()),
+            }))
+        })
+        .collect();
+
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    HttpResponse::build(status).json(json!({
+        "status": if healthy { "ok" } else { "unhealthy" },
+        "shards": {
+            "connected": connected,
+            "total": shards.len(),
+        },
+        "tasks": tasks,
+        "modules": modules,
+    }))
+}
+
+async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::new(StatusCode::NOT_FOUND, "No such endpoint"))
+}
+
+async fn task(
+    cluster: &Cluster,
+    config: &Config,
+    presence: &PresenceOverride,
+    servers: &ServerList,
+    relay: &Relay,
+    metrics: &Metrics,
+    token: &CancelToken,
+) -> Result<(),Box<dyn Error>> {
+    let settings = config.web.as_ref().ok_or("Web API needs the web section")?;
+
     let mut ssl_config = ServerConfig::new(NoClientAuth::new());
 
-    let ssl = config.web_ssl;
+    let ssl = settings.ssl;
 
     if ssl {
-        let cert_file = &mut BufReader::new(File::open(&config.web_cert)?);
-        let key_file = &mut BufReader::new(File::open(&config.web_privkey)?);
-        let cert_chain = certs(cert_file).unwrap();
-        let mut keys = rsa_private_keys(key_file).unwrap();
-        ssl_config.set_single_cert(cert_chain, keys.remove(0))?;
-    
+        let cert_chain = settings.load_cert_chain().map_err(|err| format!("{:#}", err))?;
+        let key = settings.load_private_key().map_err(|err| format!("{:#}", err))?;
+        ssl_config.set_single_cert(cert_chain, key)?;
     }
 
-    let addr = format!("{}:{}", &config.web_hostname, &config.web_port);
+    let addr = format!("{}:{}", &settings.hostname, &settings.port);
 
     info!("Running web thread {}", addr);
-    let data = web::Data::new(Mutex::new(BotData {
-        http: TwilightHttp::new(&config.discord_token),
-        token: config.botapi_token.clone(),
-    }));
+    let data = web::Data::new(BotData {
+        http: TwilightHttp::new(&config.discord.token),
+        cluster: cluster.clone(),
+        presence: presence.clone(),
+        servers: if config.mysql.is_some() { Some(servers.clone()) } else { None },
+        relay: relay.clone(),
+        metrics: metrics.clone(),
+        keys: settings.api_keys(),
+        channels: settings.channels.clone(),
+        default_channel: settings.default_channel.as_ref().and_then(|alias| {
+            let id = settings.channels.get(alias).copied();
+            if id.is_none() {
+                warn!("Default channel {} is not in web.channels", alias);
+            }
+            id
+        }),
+        github_channel: settings.github_channel.map(ChannelId),
+        usage: Mutex::new(HashMap::new()),
+    });
+
+    let metrics = metrics.clone();
 
     let mut server = HttpServer::new(move || {
+        let metrics = metrics.clone();
+
         App::new()
             .app_data(data.clone())
+            // Counts every response by status, errors from handlers included
+            .wrap_fn(move |req, srv| {
+                let metrics = metrics.clone();
+                let response = srv.call(req);
+
+                async move {
+                    let response = response.await;
+                    let status = match &response {
+                        Ok(response) => response.status(),
+                        Err(err) => err.as_response_error().status_code(),
+                    };
+                    metrics.api_request(status.as_u16());
+                    response
+                }
+            })
+            .route("/metrics", web::get().to(prometheus))
+            .route("/healthz", web::get().to(healthz))
+            .service(web::scope("/api/v1").configure(api::configure))
             .route("/*", web::post().to(request))
+            .default_service(web::route().to(not_found))
     })
+    // Signals go to the supervisor, which stops the server through the token
     .disable_signals();
 
     if ssl {
@@ -187,17 +562,44 @@ async fn task(config: &Config) -> Result<(),Box<dyn Error>> {
         server = server.bind(addr)?;
     }
 
-    server.run().await?;
+    let server = server.run();
+
+    match select(Box::pin(server.clone()), token.cancelled()).await {
+        Either::Left((result, _)) => result?,
+        Either::Right(_) => {
+            info!("Stopping web server");
+            server.stop(true).await;
+        }
+    }
 
     Ok(())
 }
 
-pub async fn spawn(config: Config) -> Result<()> {
-    Arbiter::spawn(async move {
-        loop {
-            task(&config)
+pub async fn spawn(
+    supervisor: &mut Supervisor,
+    cluster: &Cluster,
+    config: Config,
+    presence: PresenceOverride,
+    servers: ServerList,
+    relay: Relay,
+    metrics: Metrics,
+) -> Result<()> {
+    let cluster = cluster.clone();
+
+    supervisor.spawn("Web", move |token| {
+        let (cluster, config, presence, servers, relay, metrics) = (
+            cluster.clone(),
+            config.clone(),
+            presence.clone(),
+            servers.clone(),
+            relay.clone(),
+            metrics.clone(),
+        );
+
+        async move {
+            task(&cluster, &config, &presence, &servers, &relay, &metrics, &token)
                 .await
-                .unwrap_or_else(|err| warn!("Web task failed: {}", err));
+                .map_err(|err| anyhow!("{}", err))
         }
     });
 
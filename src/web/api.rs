@@ -0,0 +1,146 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use twilight_gateway::shard::Stage;
+
+use super::{
+    error::ApiError, parse_body, post_github, require, send_message, set_presence, BotData,
+    MessageBody, PresenceBody,
+};
+use crate::utils::config::ApiAction;
+
+const OPENAPI: &str = include_str!("../../openapi.yaml");
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RelayQuery {
+    token: Option<String>,
+    cursor: Option<u64>,
+    channel: Option<String>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/health", web::get().to(health))
+        .route("/openapi.yaml", web::get().to(openapi))
+        .route("/messages", web::post().to(messages))
+        .route("/presence", web::post().to(presence))
+        .route("/servers", web::get().to(servers))
+        .route("/relay", web::get().to(relay))
+        .route("/hooks/github", web::post().to(github));
+}
+
+async fn health(data: web::Data<BotData>) -> HttpResponse {
+    let shards = data.cluster.info();
+    let connected = shards
+        .values()
+        .filter(|info| info.stage() == Stage::Connected)
+        .count();
+
+    HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+        "gateway": !shards.is_empty() && connected == shards.len(),
+        "shards": {
+            "connected": connected,
+            "total": shards.len(),
+        },
+        "mysql": data.servers.is_some(),
+    }))
+}
+
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/yaml")
+        .body(OPENAPI)
+}
+
+async fn messages(
+    req: HttpRequest,
+    query: web::Query<TokenQuery>,
+    body: bytes::Bytes,
+    data: web::Data<BotData>,
+) -> Result<HttpResponse, ApiError> {
+    let key = data.authorize(&req, query.token.as_deref())?;
+
+    let body: MessageBody = parse_body(&req, &body)?;
+    let message = send_message(&data, key, &body).await?;
+
+    Ok(HttpResponse::Created().json(json!({
+        "id": message.id,
+        "channel_id": message.channel_id,
+    })))
+}
+
+async fn presence(
+    req: HttpRequest,
+    query: web::Query<TokenQuery>,
+    body: bytes::Bytes,
+    data: web::Data<BotData>,
+) -> Result<HttpResponse, ApiError> {
+    let key = data.authorize(&req, query.token.as_deref())?;
+
+    let body: PresenceBody = parse_body(&req, &body)?;
+    set_presence(&data, key, &body).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "activity": body.activity,
+        "expires_in": body.activity_expiry,
+    })))
+}
+
+async fn servers(
+    req: HttpRequest,
+    query: web::Query<TokenQuery>,
+    data: web::Data<BotData>,
+) -> Result<HttpResponse, ApiError> {
+    let key = data.authorize(&req, query.token.as_deref())?;
+    require(key, ApiAction::Servers)?;
+
+    let servers = data
+        .servers
+        .as_ref()
+        .ok_or_else(|| ApiError::unavailable("MySQL module is disabled"))?;
+
+    Ok(HttpResponse::Ok().json(servers.get()))
+}
+
+async fn relay(
+    req: HttpRequest,
+    query: web::Query<RelayQuery>,
+    data: web::Data<BotData>,
+) -> Result<HttpResponse, ApiError> {
+    let key = data.authorize(&req, query.token.as_deref())?;
+    require(key, ApiAction::Relay)?;
+
+    let batch = data.relay.since(query.cursor, |message| {
+        let allowed = data
+            .channels
+            .get(&message.channel)
+            .map_or(false, |id| key.allows_channel(*id));
+
+        allowed
+            && query
+                .channel
+                .as_ref()
+                .map_or(true, |channel| *channel == message.channel)
+    });
+
+    Ok(HttpResponse::Ok().json(batch))
+}
+
+async fn github(
+    req: HttpRequest,
+    query: web::Query<TokenQuery>,
+    body: bytes::Bytes,
+    data: web::Data<BotData>,
+) -> Result<HttpResponse, ApiError> {
+    let key = data.authorize(&req, query.token.as_deref())?;
+
+    post_github(&data, key, &body).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
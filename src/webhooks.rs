@@ -0,0 +1,200 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::client::Client;
+use anyhow::{anyhow, Context, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use hmac::{Hmac, Mac, NewMac};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+
+use actix_rt::Arbiter;
+
+use crate::events::Module;
+use crate::utils::config::{Config, Webhook, WebhookEvent};
+
+const MAX_ATTEMPTS: u32 = 6;
+const SIGNATURE_HEADER: &str = "X-Kleinerbot-Signature";
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    url: &'a str,
+    error: String,
+    payload: &'a Value,
+}
+
+fn sign(secret: &str, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .map_err(|_| anyhow!("Invalid webhook secret"))?;
+    mac.update(body);
+    Ok(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+async fn post(client: &Client, hook: &Webhook, body: &[u8]) -> Result<()> {
+    let response = client
+        .post(&hook.url)
+        .header("Content-Type", "application/json")
+        .header(SIGNATURE_HEADER, sign(&hook.secret, body)?)
+        .send_body(body.to_vec())
+        .await
+        .map_err(|err| anyhow!("Request failed: {}", err))?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("Endpoint returned {}", response.status()));
+    }
+
+    Ok(())
+}
+
+fn dead_letter(path: &str, hook: &Webhook, payload: &Value, error: String) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context("Can't open dead letter log")?;
+
+    let line = serde_json::to_string(&DeadLetter {
+        url: &hook.url,
+        error,
+        payload,
+    })?;
+
+    writeln!(file, "{}", line)?;
+
+    Ok(())
+}
+
+// Retries with exponential backoff, gives up into the dead letter log
+async fn deliver(client: Client, hook: Webhook, payload: Value, dead_letter_path: String) {
+    let body = payload.to_string().into_bytes();
+    let mut delay = Duration::from_secs(1);
+    let mut error = String::new();
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match post(&client, &hook, &body).await {
+            Ok(()) => return,
+            Err(err) => {
+                warn!(
+                    "Webhook {} attempt {}/{} failed: {}",
+                    hook.url, attempt, MAX_ATTEMPTS, err
+                );
+                error = err.to_string();
+            }
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            actix_rt::time::delay_for(delay).await;
+            delay *= 2;
+        }
+    }
+
+    dead_letter(&dead_letter_path, &hook, &payload, error)
+        .unwrap_or_else(|err| warn!("Webhook dead letter failed: {}", err));
+}
+
+fn envelope(kind: WebhookEvent, data: Value) -> Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+
+    json!({
+        "event": kind,
+        "timestamp": timestamp,
+        "data": data,
+    })
+}
+
+pub struct Webhooks {
+    client: Client,
+    config: Config,
+}
+
+impl Webhooks {
+    pub fn new(config: Config) -> Self {
+        Self {
+            client: Client::default(),
+            config,
+        }
+    }
+
+    fn notification(&self, cache: &InMemoryCache, event: &Event) -> Result<Option<(WebhookEvent, Value)>> {
+        let notification = match event {
+            Event::MemberAdd(member) => {
+                Some((WebhookEvent::MemberAdd, serde_json::to_value(&member.0)?))
+            }
+            Event::MessageDelete(msg) => {
+                Some((WebhookEvent::MessageDelete, serde_json::to_value(msg)?))
+            }
+            Event::VoiceStateUpdate(vcstate) => {
+                let state = &vcstate.0;
+                match (state.guild_id, state.channel_id) {
+                    (Some(guild_id), Some(channel_id)) => {
+                        // The cache isn't updated yet, so it still has the previous channel
+                        let previous = cache
+                            .voice_state(state.user_id, guild_id)
+                            .and_then(|previous| previous.channel_id);
+                        if previous != Some(channel_id) {
+                            Some((WebhookEvent::VoiceJoin, serde_json::to_value(state)?))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        Ok(notification)
+    }
+}
+
+impl Module for Webhooks {
+    fn name(&self) -> &'static str {
+        "Webhooks"
+    }
+
+    fn on_event<'a>(
+        &'a mut self,
+        cache: &'a InMemoryCache,
+        _shard_id: u64,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            if let Some((kind, data)) = self.notification(cache, event)? {
+                let payload = envelope(kind, data);
+
+                for hook in self
+                    .config
+                    .webhooks
+                    .iter()
+                    .filter(|hook| hook.events.contains(&kind))
+                {
+                    info!("Webhook: Sending {:?} to {}", kind, hook.url);
+
+                    Arbiter::spawn(deliver(
+                        self.client.clone(),
+                        hook.clone(),
+                        payload.clone(),
+                        self.config.webhooks_dead_letter.clone(),
+                    ));
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        self.config = config.clone();
+        Ok(())
+    }
+}